@@ -80,7 +80,7 @@ mwr r1 r0
 
 xor r3 r3
 xor r1 r1
-.org 0x30
+.align 0x10 ; start print_char_loop on a new 16 byte page
 
 print_char_loop:
 ; r1 offset on the screen
@@ -0,0 +1,97 @@
+/// A register an instruction accesses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// The register given as the first argument
+    A,
+    /// The register given as the second argument
+    B,
+    R0,
+    /// Calc operation (used by cal)
+    Co,
+    /// Data bank (used by mwr and mrd)
+    Db,
+    /// Program bank
+    Pb,
+}
+impl Register {
+    /// The general purpose register (0-3) this refers to in an encoded instruction
+    pub fn gpr(self, byte: u8) -> Option<u8> {
+        match self {
+            Self::A => Some(byte >> 2 & 0x03),
+            Self::B => Some(byte & 0x03),
+            Self::R0 => Some(0),
+            Self::Co | Self::Db | Self::Pb => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagEffect {
+    None,
+    Set,
+    Clear,
+    Flip,
+    /// Set or cleared depending on the result (overflow or comparison)
+    Write,
+    /// Read to decide if a branch is taken
+    Consume,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemEffect {
+    None,
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlFlow {
+    /// Continues with the next instruction
+    Next,
+    /// Branches to an address in the current 16 byte page when the flag is not set
+    Branch,
+    /// Jumps to the address in a register when the flag is not set
+    Jump,
+    /// Always continues at address 0 of the bank in a register
+    BankSwitch,
+}
+
+/// What an instruction reads and writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Effects {
+    pub reads: &'static [Register],
+    /// lil and lih only replace half of r0 but are treated as writing r0 because they are used in pairs
+    pub writes: &'static [Register],
+    pub flag: FlagEffect,
+    pub memory: MemEffect,
+    pub control: ControlFlow,
+}
+impl Effects {
+    pub const NONE: Self = Self {
+        reads: &[],
+        writes: &[],
+        flag: FlagEffect::None,
+        memory: MemEffect::None,
+        control: ControlFlow::Next,
+    };
+}
+
+macro_rules! effects_impl {
+    (
+        $(r($($r:ident)*))?
+        $(w($($w:ident)*))?
+        $(flag($flag:ident))?
+        $(mem($mem:ident))?
+        $(flow($flow:ident))?
+    ) => {
+        crate::Effects {
+            $(reads: &[$(crate::Register::$r),*],)?
+            $(writes: &[$(crate::Register::$w),*],)?
+            $(flag: crate::FlagEffect::$flag,)?
+            $(memory: crate::MemEffect::$mem,)?
+            $(control: crate::ControlFlow::$flow,)?
+            ..crate::Effects::NONE
+        }
+    };
+}
+pub(crate) use effects_impl as effects;
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use crate::{instruction_set, ConstOpArg};
 
 #[derive(Clone, Copy)]
@@ -20,14 +22,17 @@ pub enum Architecture {
     Nna8v1,
     Nna8v2,
 }
-impl Architecture {
-    pub fn from_str(str: &str) -> Option<Self> {
+impl std::str::FromStr for Architecture {
+    type Err = ();
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
         match str {
-            "nna8v1" => Some(Self::Nna8v1),
-            "nna8v2" => Some(Self::Nna8v2),
-            _ => None,
+            "nna8v1" => Ok(Self::Nna8v1),
+            "nna8v2" => Ok(Self::Nna8v2),
+            _ => Err(()),
         }
     }
+}
+impl Architecture {
     ///How many bytes of memory the arch can address
     pub fn addressable_size(self) -> usize {
         match self {
@@ -35,33 +40,55 @@ impl Architecture {
             Self::Nna8v2 => 65536,
         }
     }
+
+    /// The addresses of a bank that are memory (the rest is mapped to peripherals)
+    pub fn memory_range(self, bank: u8) -> Range<usize> {
+        match self {
+            // keyboard and video memory
+            Self::Nna8v1 => 0..0xEE,
+            // the IO bank
+            Self::Nna8v2 if bank == 0xFF => 0..0,
+            Self::Nna8v2 => 0..256,
+        }
+    }
+
+    /// If programs can write to the memory of a bank (RAM or EEPROM)
+    pub fn is_writable(self, bank: u8) -> bool {
+        match self {
+            Self::Nna8v1 => true,
+            // flash is below 0x80, RAM up to the IO bank
+            Self::Nna8v2 => (0x80..0xFF).contains(&bank),
+        }
+    }
 }
 
+// Instructions take 1 cycle unless noted otherwise using [cycles] or [cycles, cycles when branching]
+// {...} describes the registers the instruction reads (r) and writes (w) and how it affects the flag, memory and control flow
 instruction_set! {
     pub Nna8v1 {
         // zero
             "nop"(0x00)(),
             "brk"(0x04)(),
-            "flf"(0x08)(),
-            "clf"(0x0C)(),
-            "jmp"(0x01)("addr":Reg,()),
-            "inc"(0x02)("reg":Reg,()),
-            "dec"(0x03)("reg":Reg,()),
-        "lil"(0x10)("value":bit4),
-        "lih"(0x20)("value":bit4),
-        "mwr"(0x30)("reg":Reg,"addr":Reg),
-        "mrd"(0x40)("reg":Reg,"addr":Reg),
-        "mov"(0x50)("dest":Reg, "source":Reg),
-        "bra"(0x60)("addr":bit4),
-        "rol"(0x70)("a":Reg, "b":Reg),
-        "eq" (0x80)("a":Reg, "b":Reg),
-        "gt" (0x90)("a":Reg, "b":Reg),
-        "add"(0xA0)("source":Reg, "a":Reg),
-        "mul"(0xB0)("source":Reg, "a":Reg),
-        "and"(0xC0)("source":Reg, "a":Reg),
-        "not"(0xD0)("a":Reg, "b":Reg),
-        "or" (0xE0)("source":Reg, "a":Reg),
-        "xor"(0xF0)("source":Reg, "a":Reg)
+            "flf"(0x08)(){flag(Flip)},
+            "clf"(0x0C)(){flag(Clear)},
+            "jmp"(0x01)("addr":Reg,()){r(A) flag(Consume) flow(Jump)},
+            "inc"(0x02)("reg":Reg,()){r(A) w(A) flag(Write)},
+            "dec"(0x03)("reg":Reg,()){r(A) w(A) flag(Write)},
+        "lil"(0x10)("value":bit4){w(R0)},
+        "lih"(0x20)("value":bit4){w(R0)},
+        "mwr"(0x30)("reg":Reg,"addr":Reg)[2]{r(A B) mem(Write)},
+        "mrd"(0x40)("reg":Reg,"addr":Reg)[2]{r(B) w(A) mem(Read)},
+        "mov"(0x50)("dest":Reg, "source":Reg){r(B) w(A)},
+        "bra"(0x60)("addr":bit4){flag(Consume) flow(Branch)},
+        "rol"(0x70)("a":Reg, "b":Reg){r(A B) w(A)},
+        "eq" (0x80)("a":Reg, "b":Reg){r(A B) flag(Write)},
+        "gt" (0x90)("a":Reg, "b":Reg){r(A B) flag(Write)},
+        "add"(0xA0)("source":Reg, "a":Reg){r(A B) w(A) flag(Write)},
+        "mul"(0xB0)("source":Reg, "a":Reg){r(A B) w(A) flag(Write)},
+        "and"(0xC0)("source":Reg, "a":Reg){r(A B) w(A)},
+        "not"(0xD0)("a":Reg, "b":Reg){r(B) w(A)},
+        "or" (0xE0)("source":Reg, "a":Reg){r(A B) w(A)},
+        "xor"(0xF0)("source":Reg, "a":Reg){r(A B) w(A)}
     }
 
 }
@@ -72,27 +99,101 @@ instruction_set! {
             "brk"(0x04)(),
             //? 0x08
             //? 0x0C
-            "jmp"(0x01)("addr":Reg,()),
-            "mpb"(0x02)("bank":Reg,()),
-            "mdb"(0x03)("bank":Reg,()),
-        "eq"(0x10)("a":Reg,"b":Reg),
-        "gt"(0x20)("a":Reg,"b":Reg),
+            "jmp"(0x01)("addr":Reg,())[1, 2]{r(A) flag(Consume) flow(Jump)},
+            "mpb"(0x02)("bank":Reg,()){r(A) w(Pb) flow(BankSwitch)},
+            "mdb"(0x03)("bank":Reg,()){r(A) w(Db)},
+        "eq"(0x10)("a":Reg,"b":Reg){r(A B) flag(Write)},
+        "gt"(0x20)("a":Reg,"b":Reg){r(A B) flag(Write)},
         //flg
-            "flf"(0x30)(), // 00
-            "sef"(0x3C)(), // 11
-            "clf"(0x34)(), // 01
+            "flf"(0x30)(){flag(Flip)}, // 00
+            "sef"(0x3C)(){flag(Set)}, // 11
+            "clf"(0x34)(){flag(Clear)}, // 01
         //? 0x4
-        "bra"(0x50)("addr":bit4),
-        "mco"(0x60)("co":CalOp),
-        "mwr"(0x70)("reg":Reg,"addr":Reg),
-        "mrd"(0x80)("reg":Reg,"addr":Reg),
-        "lil"(0x90)("val":bit4),
-        "lih"(0xA0)("val":bit4),
-        "mov"(0xB0)("dest":Reg,"src":Reg),
-        "cal"(0xC0)("a":Reg,"b":Reg),
-        "xor"(0xD0)("a":Reg,"b":Reg),
-        "inc"(0xE0)("reg":Reg,"amount":bit2nz),
-        "dec"(0xF0)("reg":Reg,"amount":bit2nz)
+        "bra"(0x50)("addr":bit4)[1, 2]{flag(Consume) flow(Branch)},
+        "mco"(0x60)("co":CalOp){w(Co)},
+        "mwr"(0x70)("reg":Reg,"addr":Reg)[2]{r(A B Db) mem(Write)},
+        "mrd"(0x80)("reg":Reg,"addr":Reg)[2]{r(B Db) w(A) mem(Read)},
+        "lil"(0x90)("val":bit4){w(R0)},
+        "lih"(0xA0)("val":bit4){w(R0)},
+        "mov"(0xB0)("dest":Reg,"src":Reg){r(B) w(A)},
+        "cal"(0xC0)("a":Reg,"b":Reg){r(A B Co) w(A) flag(Write)},
+        "xor"(0xD0)("a":Reg,"b":Reg){r(A B) w(A)},
+        "inc"(0xE0)("reg":Reg,"amount":bit2nz){r(A) w(A) flag(Write)},
+        "dec"(0xF0)("reg":Reg,"amount":bit2nz){r(A) w(A) flag(Write)}
+
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Architecture, Nna8v1, Nna8v2};
+    use crate::{Arch, ControlFlow, Cycles, FlagEffect, MemEffect, Register};
+
+    #[test]
+    fn decode() {
+        let name = |op: Option<Nna8v1>| op.map(|op| op.name());
+        assert_eq!(name(Nna8v1::decode(0x00)), Some("nop"));
+        assert_eq!(name(Nna8v1::decode(0x0C)), Some("clf"));
+        assert_eq!(name(Nna8v1::decode(0x0D)), Some("jmp"));
+        assert_eq!(name(Nna8v1::decode(0x1F)), Some("lil"));
+        assert_eq!(name(Nna8v1::decode(0xF6)), Some("xor"));
+
+        let name = |op: Option<Nna8v2>| op.map(|op| op.name());
+        assert_eq!(name(Nna8v2::decode(0x0B)), Some("mdb"));
+        assert_eq!(name(Nna8v2::decode(0x3C)), Some("sef"));
+        assert_eq!(name(Nna8v2::decode(0x08)), None);
+        assert_eq!(name(Nna8v2::decode(0x40)), None);
+    }
+
+    #[test]
+    fn format_encoded() {
+        let format = |byte| {
+            let op = Nna8v2::decode(byte).unwrap();
+            format!("{} {}", op.name(), op.args().format_encoded(byte))
+        };
+        assert_eq!(format(0xB4), "mov r1 r0");
+        assert_eq!(format(0x9A), "lil 0xa");
+        assert_eq!(format(0xE6), "inc r1 0x3");
+        assert_eq!(format(0x61), "mco sub");
+        assert_eq!(format(0x0D), "jmp r3");
+        assert_eq!(format(0x34), "clf ");
+    }
+
+    #[test]
+    fn cycles() {
+        let cycles = |name| Nna8v2::try_from_str(name).unwrap().cycles();
+        assert_eq!(cycles("mov"), Cycles { base: 1, taken: 1 });
+        assert_eq!(cycles("mrd"), Cycles { base: 2, taken: 2 });
+        assert_eq!(cycles("bra"), Cycles { base: 1, taken: 2 });
+        assert_eq!(Nna8v1::try_from_str("bra").unwrap().cycles().max(), 1);
+    }
+
+    #[test]
+    fn memory_map() {
+        assert_eq!(Architecture::Nna8v1.memory_range(0), 0..0xEE);
+        assert!(Architecture::Nna8v1.is_writable(0));
+        assert_eq!(Architecture::Nna8v2.memory_range(0x80), 0..256);
+        assert!(Architecture::Nna8v2.memory_range(0xFF).is_empty());
+        assert!(!Architecture::Nna8v2.is_writable(0x7F));
+        assert!(Architecture::Nna8v2.is_writable(0x80));
+        assert!(!Architecture::Nna8v2.is_writable(0xFF));
+    }
+
+    #[test]
+    fn effects() {
+        let effects = |name| Nna8v2::try_from_str(name).unwrap().effects();
+        let cal = effects("cal");
+        assert_eq!(cal.reads, &[Register::A, Register::B, Register::Co]);
+        assert_eq!(cal.writes, &[Register::A]);
+        assert_eq!(cal.flag, FlagEffect::Write);
+        assert_eq!(effects("mrd").memory, MemEffect::Read);
+        assert_eq!(effects("mpb").control, ControlFlow::BankSwitch);
+        assert_eq!(effects("nop"), crate::Effects::NONE);
 
+        let jmp = Nna8v1::decode(0x0D).unwrap().effects();
+        assert_eq!(jmp.reads[0].gpr(0x0D), Some(3));
+        assert_eq!(jmp.control, ControlFlow::Jump);
+        assert_eq!(jmp.flag, FlagEffect::Consume);
+        assert!(jmp.writes.is_empty());
     }
 }
@@ -10,14 +10,57 @@ pub use instruction_sets::Architecture;
 mod opargs;
 pub use opargs::*;
 
+mod effects;
+pub(crate) use effects::effects;
+pub use effects::{ControlFlow, Effects, FlagEffect, MemEffect, Register};
+
+/// How many cycles an instruction takes to execute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cycles {
+    pub base: u8,
+    /// When the instruction branches (same as base for instructions that don't branch)
+    pub taken: u8,
+}
+impl Cycles {
+    pub fn min(self) -> u8 {
+        self.base.min(self.taken)
+    }
+    pub fn max(self) -> u8 {
+        self.base.max(self.taken)
+    }
+}
+
 pub trait Arch: Sized + Copy {
     const BANKS: bool = false;
 
     fn try_from_str(str: &str) -> Option<Self>;
+    /// Finds the instruction an encoded byte belongs to
+    fn decode(byte: u8) -> Option<Self>;
     fn args(self) -> OpArgs;
     fn name(self) -> &'static str;
+    fn cycles(self) -> Cycles;
+    fn effects(self) -> Effects;
 }
 
+macro_rules! cycles_impl {
+    () => {
+        crate::Cycles { base: 1, taken: 1 }
+    };
+    ($base:literal) => {
+        crate::Cycles {
+            base: $base,
+            taken: $base,
+        }
+    };
+    ($base:literal, $taken:literal) => {
+        crate::Cycles {
+            base: $base,
+            taken: $taken,
+        }
+    };
+}
+pub(crate) use cycles_impl as cycles;
+
 macro_rules! iset_args_impl {
     ($([$arg:ident])*) => {
         $(
@@ -31,7 +74,7 @@ macro_rules! iset_args_impl {
 pub(crate) use iset_args_impl as iset_args;
 
 macro_rules! instruction_set_impl {
-    ( $vis:vis $name:ident $([$arg:ident])*{ $($opname:literal($opcode:literal)$opargs:tt),*}) => {
+    ( $vis:vis $name:ident $([$arg:ident])*{ $($opname:literal($opcode:literal)$opargs:tt $([$($cycles:literal),+])? $({$($effects:tt)*})?),*}) => {
         #[derive(Copy, Clone)]
         $vis struct $name(u8);
         impl crate::Arch for $name {
@@ -47,6 +90,12 @@ macro_rules! instruction_set_impl {
                 }
             }
 
+            fn decode(byte: u8) -> Option<Self> {
+                [$(Self($opcode)),*]
+                    .into_iter()
+                    .find(|op| byte & op.args().opcode_mask() == op.0)
+            }
+
             fn args(self) -> crate::OpArgs {
                 match self.0 {
                     $(
@@ -63,6 +112,22 @@ macro_rules! instruction_set_impl {
                         _ => unreachable!(),
                     }
             }
+            fn cycles(self) -> crate::Cycles {
+                match self.0 {
+                    $(
+                        $opcode => crate::cycles!($($($cycles),+)?),
+                    )*
+                    _ => unreachable!(),
+                }
+            }
+            fn effects(self) -> crate::Effects {
+                match self.0 {
+                    $(
+                        $opcode => crate::effects!($($($effects)*)?),
+                    )*
+                    _ => unreachable!(),
+                }
+            }
             crate::iset_args!($($arg),*);
 
         }
@@ -72,9 +137,9 @@ macro_rules! instruction_set_impl {
                 self.0
             }
         }
-        impl Into<u8> for $name {
-            fn into(self) -> u8 {
-                self.0
+        impl From<$name> for u8 {
+            fn from(value: $name) -> u8 {
+                value.0
             }
         }
         impl std::ops::Deref for $name {
@@ -99,6 +99,41 @@ pub enum OpArgs {
     Arg(BigOpArg),
     ArgArg(OpArg, OpArg),
 }
+impl OpArg {
+    /// Formats an encoded argument the way it is written in assembly
+    fn format_encoded(self, value: u8) -> Option<String> {
+        match self.ty {
+            OpArgType::None => None,
+            OpArgType::Value { nz } => Some(format!("{:#x}", value + nz as u8)),
+            OpArgType::Const(c) => Some(c.variants.get(value as usize).unwrap_or(&"?").to_string()),
+        }
+    }
+}
+impl OpArgs {
+    /// The arguments of an encoded instruction the way they are written in assembly (for example `r1 r0`)
+    pub fn format_encoded(self, byte: u8) -> String {
+        let args = match self {
+            Self::Arg(a) => vec![a.0.format_encoded(byte & 0x0F)],
+            Self::ArgArg(a, b) => vec![
+                a.format_encoded(byte >> 2 & 0x03),
+                b.format_encoded(byte & 0x03),
+            ],
+        };
+        args.into_iter().flatten().collect::<Vec<_>>().join(" ")
+    }
+
+    /// The bits of an encoded instruction that aren't arguments
+    pub fn opcode_mask(self) -> u8 {
+        let fixed = |arg: OpArg, mask: u8| match arg.ty {
+            OpArgType::None => mask,
+            _ => 0,
+        };
+        match self {
+            Self::Arg(a) => 0xF0 | fixed(a.0, 0x0F),
+            Self::ArgArg(a, b) => 0xF0 | fixed(a, 0x0C) | fixed(b, 0x03),
+        }
+    }
+}
 impl Display for OpArgs {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -0,0 +1,491 @@
+//! What the language server knows about a document. (independent of the protocol)
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use libnna::Architecture;
+use nnaasm::{assemble, project, IntoAsmError, Located, Location, Options, Severity, Symbol};
+
+use crate::isa;
+
+/// An error, warning or lint in the document
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+    pub notes: Vec<Located<String>>,
+}
+impl From<nnaasm::AsmError<'_>> for Diagnostic {
+    fn from(err: nnaasm::AsmError) -> Self {
+        Self {
+            severity: err.severity,
+            location: err.location,
+            message: err.message,
+            notes: err.notes,
+        }
+    }
+}
+
+/// The labels of the last version of a document that could be assembled
+#[derive(Default)]
+pub struct Labels {
+    pub definitions: Vec<Located<Symbol>>,
+    /// The unique names of referenced labels
+    pub references: Vec<Located<Box<str>>>,
+}
+
+pub struct Analysis {
+    pub arch: Architecture,
+    pub diagnostics: Vec<Diagnostic>,
+    /// None when the document couldn't be assembled
+    pub labels: Option<Labels>,
+}
+
+/// The architecture set using `.arch` or the default
+pub fn arch(text: &str, default: Architecture) -> Architecture {
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix(".arch"))
+        .find_map(|arch| Architecture::from_str(arch.trim().trim_matches('"')).ok())
+        .unwrap_or(default)
+}
+
+/// The architecture of the file in the closest `nna.toml` above it
+pub fn project_arch(path: &Path) -> Option<Architecture> {
+    let manifest = path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(project::MANIFEST))
+        .find(|manifest| manifest.is_file())?;
+    let targets = project::load(&manifest).ok()?;
+    targets
+        .into_iter()
+        .find(|target| target.input == path)
+        .map(|target| target.arch)
+}
+
+pub fn analyze(text: &str, path: Option<PathBuf>, default_arch: Architecture) -> Analysis {
+    let options = Options {
+        path,
+        ..Options::new(default_arch)
+    };
+    let (diagnostics, labels) = match assemble(text, &options) {
+        Ok(program) => {
+            let labels = Labels {
+                definitions: program.symbols,
+                references: program.references,
+            };
+            let diagnostics = program
+                .diagnostics
+                .into_iter()
+                .map(|diag| diag.into_asm_error(text, "".into()).into())
+                .collect();
+            (diagnostics, Some(labels))
+        }
+        Err(err) => (vec![err.into_asm_error(text, "".into()).into()], None),
+    };
+    Analysis {
+        arch: arch(text, default_arch),
+        diagnostics,
+        labels,
+    }
+}
+
+fn contains(location: &Location, line: usize, col: usize) -> bool {
+    let (loc_line, range): (usize, Range<usize>) = location.clone().into();
+    loc_line == line && range.start <= col && col <= range.end
+}
+
+/// The token under the cursor (without a leading `&` and trailing `:`)
+pub fn word_at(text: &str, line: usize, col: usize) -> Option<&str> {
+    let line = text.lines().nth(line)?;
+    let line = line.split(';').next().unwrap_or_default();
+    let is_word = |c: char| !c.is_whitespace();
+    let col = col.min(line.len());
+    let start = line[..col].rfind(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = line[col..]
+        .find(|c| !is_word(c))
+        .map_or(line.len(), |i| col + i);
+    let word = line.get(start..end)?;
+    let word = word.strip_prefix('&').unwrap_or(word);
+    let word = word.strip_suffix(':').unwrap_or(word);
+    let word = word
+        .strip_suffix(".low")
+        .or(word.strip_suffix(".high"))
+        .unwrap_or(word);
+    (!word.is_empty()).then_some(word)
+}
+
+impl Labels {
+    /// The unique name of the label defined or referenced at the cursor
+    pub fn name_at(&self, text: &str, line: usize, col: usize) -> Option<&str> {
+        let word = word_at(text, line, col)?;
+        let defined = self
+            .definitions
+            .iter()
+            .filter(|def| contains(&def.location, line, col))
+            .map(|def| &*def.value.name);
+        let referenced = self
+            .references
+            .iter()
+            .filter(|r| contains(&r.location, line, col))
+            .map(|r| &*r.value);
+        // pseudo instructions can reference generated labels at the same location
+        defined.chain(referenced).find(|name| {
+            self.definition(name).is_some_and(|def| {
+                let display = def.value.display_name();
+                // local labels are shown with their scope
+                display == word || word.starts_with('.') && display.ends_with(word)
+            })
+        })
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Located<Symbol>> {
+        self.definitions.iter().find(|def| &*def.value.name == name)
+    }
+
+    pub fn references<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Location> {
+        self.references
+            .iter()
+            .filter(move |r| &*r.value == name)
+            .map(|r| &r.location)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutlineKind {
+    Bank,
+    Org,
+    Label,
+}
+
+/// A `.bank`, `.org` or global label and the lines that belong to it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outline {
+    pub name: String,
+    pub kind: OutlineKind,
+    pub lines: Range<usize>,
+    pub children: Vec<Outline>,
+}
+
+/// The `.bank` and `.org` blocks of a document with the global labels in them
+pub fn outline(text: &str) -> Vec<Outline> {
+    let mut banks: Vec<Outline> = Vec::new();
+    let mut orgs: Vec<Outline> = Vec::new();
+    let mut labels: Vec<Outline> = Vec::new();
+    let line_count = text.lines().count();
+
+    fn close(items: &mut [Outline], line: usize) {
+        if let Some(last) = items.last_mut() {
+            last.lines.end = line;
+        }
+    }
+    fn close_org(orgs: &mut Vec<Outline>, labels: &mut Vec<Outline>, line: usize) {
+        close(labels, line);
+        match orgs.last_mut() {
+            Some(org) => org.children.append(labels),
+            None => orgs.append(labels),
+        }
+        close(orgs, line);
+    }
+    fn close_bank(banks: &mut Vec<Outline>, orgs: &mut Vec<Outline>, line: usize) {
+        match banks.last_mut() {
+            Some(bank) => bank.children.append(orgs),
+            None => banks.append(orgs),
+        }
+        close(banks, line);
+    }
+
+    for (i, line) in text.lines().enumerate() {
+        let code = line.split(';').next().unwrap_or_default();
+        let mut tokens = code.split_whitespace();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+        let item = |kind| Outline {
+            name: format!("{} {}", first, tokens.clone().next().unwrap_or_default()),
+            kind,
+            lines: i..line_count,
+            children: Vec::new(),
+        };
+        match first {
+            ".bank" => {
+                close_org(&mut orgs, &mut labels, i);
+                close_bank(&mut banks, &mut orgs, i);
+                banks.push(item(OutlineKind::Bank));
+            }
+            ".org" => {
+                close_org(&mut orgs, &mut labels, i);
+                orgs.push(item(OutlineKind::Org));
+            }
+            _ => {
+                if let Some(label) = first.strip_suffix(':') {
+                    if !label.starts_with(['.', '+', '-']) {
+                        close(&mut labels, i);
+                        labels.push(Outline {
+                            name: label.to_string(),
+                            kind: OutlineKind::Label,
+                            lines: i..line_count,
+                            children: Vec::new(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    close_org(&mut orgs, &mut labels, line_count);
+    close_bank(&mut banks, &mut orgs, line_count);
+    banks
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SuggestionKind {
+    Instruction,
+    Directive,
+    Value,
+    Label,
+}
+
+#[derive(Debug)]
+pub struct Suggestion {
+    pub label: String,
+    pub kind: SuggestionKind,
+    pub detail: String,
+    pub documentation: Option<String>,
+}
+
+/// What can be written at the end of `prefix` (the line up to the cursor)
+pub fn complete(arch: Architecture, prefix: &str, labels: Option<&Labels>) -> Vec<Suggestion> {
+    if prefix.contains(';') {
+        return Vec::new();
+    }
+    let mut tokens: Vec<&str> = prefix
+        .split_whitespace()
+        .skip_while(|token| token.ends_with(':'))
+        .collect();
+    let current = match prefix.ends_with(char::is_whitespace) {
+        true => "",
+        false => tokens.pop().unwrap_or_default(),
+    };
+    let value = |label: &str, detail: &str| Suggestion {
+        label: label.to_string(),
+        kind: SuggestionKind::Value,
+        detail: detail.to_string(),
+        documentation: None,
+    };
+
+    let Some(mnemonic) = tokens.first() else {
+        if current.starts_with('.') {
+            return nnaasm::asm::DIRECTIVES
+                .iter()
+                .map(|(name, _)| Suggestion {
+                    label: format!(".{}", name),
+                    kind: SuggestionKind::Directive,
+                    detail: "directive".to_string(),
+                    documentation: None,
+                })
+                .collect();
+        }
+        let instructions = isa::instructions(arch).into_iter().map(|inst| Suggestion {
+            label: inst.name.to_string(),
+            kind: SuggestionKind::Instruction,
+            detail: inst.signature(),
+            documentation: Some(inst.hover()),
+        });
+        let pseudo = isa::PSEUDO.iter().map(|(name, doc)| Suggestion {
+            label: name.to_string(),
+            kind: SuggestionKind::Instruction,
+            detail: "pseudo instruction".to_string(),
+            documentation: Some(doc.to_string()),
+        });
+        return instructions.chain(pseudo).collect();
+    };
+
+    let index = tokens.len() - 1;
+    if current.starts_with('&') || *mnemonic == "call" {
+        let prefix = if current.starts_with('&') { "&" } else { "" };
+        return labels
+            .map(|labels| labels.definitions.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|def| def.value.is_global())
+            .map(|def| Suggestion {
+                label: format!("{}{}", prefix, def.value.display_name()),
+                kind: SuggestionKind::Label,
+                detail: format!("label at {}", def.value.addr),
+                documentation: None,
+            })
+            .collect();
+    }
+    if *mnemonic == "li" && index == 0 {
+        return isa::registers()
+            .iter()
+            .map(|reg| value(reg, "reg"))
+            .collect();
+    }
+    let Some(arg) = isa::instruction(arch, mnemonic).and_then(|inst| inst.arg_variants(index))
+    else {
+        return Vec::new();
+    };
+    arg.variants
+        .iter()
+        .filter(|variant| **variant != "?")
+        .map(|variant| value(variant, arg.name))
+        .collect()
+}
+
+/// Markdown for the instruction or label under the cursor
+pub fn hover(
+    text: &str,
+    line: usize,
+    col: usize,
+    arch: Architecture,
+    labels: &Labels,
+) -> Option<String> {
+    let word = word_at(text, line, col)?;
+    if let Some(inst) = isa::instruction(arch, word) {
+        return Some(inst.hover());
+    }
+    if let Some((_, doc)) = isa::PSEUDO.iter().find(|(name, _)| *name == word) {
+        let (signature, description) = doc.split_once("\n\n").unwrap_or((doc, ""));
+        return Some(format!("```nnaasm\n{}\n```\n{}", signature, description));
+    }
+    let name = labels.name_at(text, line, col)?;
+    let def = labels.definition(name)?;
+    Some(format!(
+        "label `{}` at `{}`",
+        def.value.display_name(),
+        def.value.addr
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use std::fs;
+
+    use super::{
+        analyze, complete, hover, outline, project_arch, word_at, OutlineKind, SuggestionKind,
+    };
+
+    const CODE: &str = r#".arch "nna8v2"
+.bank 0x0
+.org 0x00
+start:
+lil &start.low ; comment
+.loop:
+bra &.loop.low
+.org 0x10
+other:
+mov r1 r0
+"#;
+
+    #[test]
+    fn labels() {
+        let analysis = analyze(CODE, None, Architecture::Nna8v1);
+        assert_eq!(analysis.arch, Architecture::Nna8v2);
+        let labels = analysis.labels.unwrap();
+
+        assert_eq!(word_at(CODE, 4, 6), Some("start"));
+        let name = labels.name_at(CODE, 4, 6).unwrap();
+        let def = labels.definition(name).unwrap();
+        assert_eq!(def.location, (3, 0..6).into());
+        assert_eq!(labels.references(name).count(), 1);
+
+        let local = labels.name_at(CODE, 6, 6).unwrap();
+        assert_eq!(local, "start.loop");
+        assert_eq!(labels.definition(local).unwrap().location, (5, 0..6).into());
+
+        let arch = Architecture::Nna8v2;
+        let text = hover(CODE, 9, 1, arch, &labels).unwrap();
+        assert!(text.contains("mov [dest:reg] [src:reg]") && text.contains("`1011 aabb`"));
+        assert!(text.contains("Reads: `src`") && text.contains("Writes: `dest`"));
+        assert_eq!(
+            hover(CODE, 4, 6, arch, &labels).unwrap(),
+            "label `start` at `0x00:0x00`"
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let analysis = analyze(".org 0x00\nlil &missing.low\n", None, Architecture::Nna8v1);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(
+            analysis.diagnostics[0].message,
+            "label 'missing' is not defined"
+        );
+        assert!(analysis.labels.is_none());
+    }
+
+    #[test]
+    fn outlines() {
+        let outline = outline(CODE);
+        assert_eq!(outline.len(), 1);
+        let bank = &outline[0];
+        assert_eq!(bank.kind, OutlineKind::Bank);
+        assert_eq!(bank.lines, 1..10);
+        let names: Vec<_> = bank.children.iter().map(|org| org.name.as_str()).collect();
+        assert_eq!(names, [".org 0x00", ".org 0x10"]);
+        assert_eq!(bank.children[0].lines, 2..7);
+        assert_eq!(bank.children[0].children[0].name, "start");
+        assert_eq!(bank.children[1].children[0].lines, 8..10);
+    }
+
+    #[test]
+    fn completion() {
+        let labels = |s: &[super::Suggestion]| -> Vec<String> {
+            s.iter().map(|s| s.label.clone()).collect()
+        };
+        let v2 = Architecture::Nna8v2;
+        let ops = complete(v2, "lab: m", None);
+        assert!(ops
+            .iter()
+            .any(|s| s.label == "mco" && s.detail.starts_with("mco ")));
+        assert!(complete(Architecture::Nna8v1, "", None)
+            .iter()
+            .all(|s| s.label != "mco"));
+        assert_eq!(
+            labels(&complete(v2, "mov ", None)),
+            ["r0", "r1", "r2", "r3"]
+        );
+        assert_eq!(
+            labels(&complete(v2, "mov r1 r", None)),
+            ["r0", "r1", "r2", "r3"]
+        );
+        assert!(complete(v2, "mov r1 r0 ", None).is_empty());
+        assert!(labels(&complete(v2, "mco ", None)).contains(&"sub".to_string()));
+        assert_eq!(labels(&complete(v2, "li ", None)), ["r0", "r1", "r2", "r3"]);
+        assert!(complete(v2, ".o", None)
+            .iter()
+            .all(|s| s.kind == SuggestionKind::Directive));
+
+        let analysis = analyze(CODE, None, Architecture::Nna8v1);
+        assert_eq!(
+            labels(&complete(v2, "lil &", analysis.labels.as_ref())),
+            ["&start", "&other"]
+        );
+    }
+
+    #[test]
+    fn manifest_arch() {
+        let dir = std::env::temp_dir().join(format!("nna-lsp-project-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("nna.toml"),
+            "arch = \"nna8v2\"\n[[program]]\ninput = \"src/main.asm\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            project_arch(&dir.join("src/main.asm")),
+            Some(Architecture::Nna8v2)
+        );
+        // not a program of the project
+        assert_eq!(project_arch(&dir.join("src/other.asm")), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
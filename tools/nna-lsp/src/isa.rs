@@ -0,0 +1,166 @@
+//! Information about the instructions of an architecture used for completion and hover
+
+use libnna::{
+    instruction_sets::{Nna8v1, Nna8v2, Reg},
+    Arch, Architecture, ConstArg, ConstOpArg, Cycles, Effects, OpArgType, OpArgs, Register,
+};
+
+/// Pseudo instructions and what they do
+pub const PSEUDO: &[(&str, &str)] = &[
+    ("li", "li rN value|&label\n\nLoads a value or the address of a label into a register using the shortest sequence"),
+    ("call", "call label\n\nJumps to a function with the return address in r0"),
+    ("ret", "ret\n\nReturns from the current .func"),
+];
+
+pub struct Instruction {
+    pub name: &'static str,
+    pub opcode: u8,
+    pub args: OpArgs,
+    pub cycles: Cycles,
+    pub effects: Effects,
+}
+impl Instruction {
+    /// The operands as they are shown in the spec (for example `[reg:reg] {amount:2bitnz}`)
+    pub fn signature(&self) -> String {
+        format!("{} {}", self.name, self.args)
+            .trim_end()
+            .to_string()
+    }
+
+    /// The bits of the instruction with the arguments as letters (for example `1110 aabb`)
+    pub fn encoding(&self) -> String {
+        let mask = self.args.opcode_mask();
+        let mut out = String::new();
+        for bit in (0..8).rev() {
+            let c = if mask & 1 << bit != 0 {
+                if self.opcode & 1 << bit != 0 {
+                    '1'
+                } else {
+                    '0'
+                }
+            } else {
+                match (self.args, bit) {
+                    (OpArgs::Arg(_), _) => 'x',
+                    (OpArgs::ArgArg(_, _), 2 | 3) => 'a',
+                    (OpArgs::ArgArg(_, _), _) => 'b',
+                }
+            };
+            out.push(c);
+            if bit == 4 {
+                out.push(' ');
+            }
+        }
+        out
+    }
+
+    /// Markdown describing the instruction
+    pub fn hover(&self) -> String {
+        let mut text = format!(
+            "```nnaasm\n{}\n```\nEncoding: `{}` ({:#04x})\n\nCycles: {}",
+            self.signature(),
+            self.encoding(),
+            self.opcode,
+            self.cycles.base
+        );
+        if self.cycles.taken != self.cycles.base {
+            text.push_str(&format!(" ({} when taken)", self.cycles.taken));
+        }
+        // the registers given as arguments are shown with the argument's name
+        let list = |regs: &[Register]| {
+            regs.iter()
+                .map(|reg| match (reg, self.args) {
+                    (Register::A, OpArgs::ArgArg(a, _)) => format!("`{}`", a.desc),
+                    (Register::B, OpArgs::ArgArg(_, b)) => format!("`{}`", b.desc),
+                    (reg, _) => format!("{:?}", reg).to_lowercase(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if !self.effects.reads.is_empty() {
+            text.push_str(&format!("\n\nReads: {}", list(self.effects.reads)));
+        }
+        if !self.effects.writes.is_empty() {
+            text.push_str(&format!("\n\nWrites: {}", list(self.effects.writes)));
+        }
+        text
+    }
+
+    /// The constant values an argument can have (for example the registers)
+    pub fn arg_variants(&self, index: usize) -> Option<ConstArg> {
+        let ty = match (self.args, index) {
+            (OpArgs::Arg(a), 0) => a.0.ty,
+            (OpArgs::ArgArg(a, _), 0) => a.ty,
+            (OpArgs::ArgArg(_, b), 1) => b.ty,
+            _ => return None,
+        };
+        match ty {
+            OpArgType::Const(c) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+fn instructions_of<I: Arch + Into<u8>>() -> Vec<Instruction> {
+    nnaasm::asm::instructions::<I>()
+        .into_iter()
+        .map(|op| Instruction {
+            name: op.name(),
+            args: op.args(),
+            cycles: op.cycles(),
+            effects: op.effects(),
+            opcode: op.into(),
+        })
+        .collect()
+}
+
+/// Every instruction of an architecture
+pub fn instructions(arch: Architecture) -> Vec<Instruction> {
+    match arch {
+        Architecture::Nna8v1 => instructions_of::<Nna8v1>(),
+        Architecture::Nna8v2 => instructions_of::<Nna8v2>(),
+    }
+}
+
+pub fn instruction(arch: Architecture, name: &str) -> Option<Instruction> {
+    instructions(arch)
+        .into_iter()
+        .find(|inst| inst.name == name)
+}
+
+/// The names of the registers (for the first argument of `li`)
+pub fn registers() -> &'static [&'static str] {
+    Reg::VARIANTS
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::{instruction, instructions};
+
+    #[test]
+    fn encodings() {
+        let inc = instruction(Architecture::Nna8v2, "inc").unwrap();
+        assert_eq!(inc.encoding(), "1110 aabb");
+        assert_eq!(inc.signature(), "inc [reg:reg] {amount:2bitnz}");
+        assert_eq!(
+            inc.arg_variants(0).unwrap().variants,
+            ["r0", "r1", "r2", "r3"]
+        );
+        assert!(inc.arg_variants(1).is_none());
+
+        let jmp = instruction(Architecture::Nna8v1, "jmp").unwrap();
+        assert_eq!(jmp.encoding(), "0000 aa01");
+        let lil = instruction(Architecture::Nna8v2, "lil").unwrap();
+        assert_eq!(lil.encoding(), "1001 xxxx");
+
+        let names: Vec<_> = instructions(Architecture::Nna8v2)
+            .iter()
+            .map(|inst| inst.name)
+            .collect();
+        assert!(names.contains(&"mco") && names.contains(&"sef"));
+        assert!(!instructions(Architecture::Nna8v1)
+            .iter()
+            .any(|inst| inst.name == "mco"));
+    }
+}
@@ -0,0 +1,382 @@
+//! Language server for nnaasm sources
+
+mod analysis;
+mod isa;
+
+use std::{collections::HashMap, error::Error, ops::Range, str::FromStr};
+
+use analysis::{Analysis, Labels, Outline, OutlineKind, SuggestionKind};
+use libnna::Architecture;
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as _,
+    },
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse,
+    DiagnosticRelatedInformation, DiagnosticSeverity, DocumentSymbol, DocumentSymbolResponse,
+    GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, ServerCapabilities, SymbolKind,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use nnaasm::{Location, Severity};
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+    /// Labels of the last version that could be assembled
+    labels: Labels,
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, Document>,
+    /// Used for files without `.arch` that aren't part of a project
+    default_arch: Architecture,
+}
+
+/// Converts a byte column to the UTF-16 column used by the protocol
+fn to_utf16(line: &str, col: usize) -> u32 {
+    line.get(..col)
+        .unwrap_or(line)
+        .chars()
+        .map(char::len_utf16)
+        .sum::<usize>() as u32
+}
+
+/// Converts a UTF-16 column used by the protocol to a byte column
+fn from_utf16(line: &str, col: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= col as usize {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn line_of(text: &str, line: usize) -> &str {
+    text.lines().nth(line).unwrap_or_default()
+}
+
+fn range(text: &str, location: &Location) -> lsp_types::Range {
+    let (line, cols): (usize, Range<usize>) = location.clone().into();
+    let content = line_of(text, line);
+    lsp_types::Range::new(
+        Position::new(line as u32, to_utf16(content, cols.start)),
+        Position::new(line as u32, to_utf16(content, cols.end)),
+    )
+}
+
+fn lines(text: &str, lines: &Range<usize>) -> lsp_types::Range {
+    let last = lines.end.max(lines.start + 1) - 1;
+    lsp_types::Range::new(
+        Position::new(lines.start as u32, 0),
+        Position::new(last as u32, to_utf16(line_of(text, last), usize::MAX)),
+    )
+}
+
+/// The line and byte column of a position
+fn cursor(text: &str, position: Position) -> (usize, usize) {
+    let line = position.line as usize;
+    (line, from_utf16(line_of(text, line), position.character))
+}
+
+impl Server {
+    fn send(&self, message: impl Into<Message>) -> Result<(), Box<dyn Error>> {
+        self.connection.sender.send(message.into())?;
+        Ok(())
+    }
+
+    fn update(&mut self, uri: Url, text: String) -> Result<(), Box<dyn Error>> {
+        let path = uri.to_file_path().ok();
+        let arch = path
+            .as_deref()
+            .and_then(analysis::project_arch)
+            .unwrap_or(self.default_arch);
+        let analysis = analysis::analyze(&text, path, arch);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|diag| lsp_types::Diagnostic {
+                range: range(&text, &diag.location),
+                severity: Some(match diag.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                    Severity::Note => DiagnosticSeverity::INFORMATION,
+                }),
+                source: Some("nnaasm".to_string()),
+                message: diag.message.clone(),
+                related_information: Some(
+                    diag.notes
+                        .iter()
+                        .map(|note| DiagnosticRelatedInformation {
+                            location: lsp_types::Location::new(
+                                uri.clone(),
+                                range(&text, &note.location),
+                            ),
+                            message: note.value.clone(),
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            })
+            .collect();
+        let params = PublishDiagnosticsParams::new(uri.clone(), diagnostics, None);
+        self.send(Notification::new(PublishDiagnostics::METHOD.into(), params))?;
+
+        let mut labels = self
+            .documents
+            .remove(&uri)
+            .map(|doc| doc.labels)
+            .unwrap_or_default();
+        let mut analysis = analysis;
+        if let Some(new) = analysis.labels.take() {
+            labels = new;
+        }
+        self.documents.insert(
+            uri,
+            Document {
+                text,
+                analysis,
+                labels,
+            },
+        );
+        Ok(())
+    }
+
+    fn notification(&mut self, not: Notification) -> Result<(), Box<dyn Error>> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(not.params)?;
+                self.update(params.text_document.uri, params.text_document.text)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(not.params)?;
+                // full sync so the last change is the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.update(params.text_document.uri, change.text)?;
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(not.params)?;
+                self.documents.remove(&params.text_document.uri);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn request(&self, req: Request) -> Result<(), Box<dyn Error>> {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            Completion::METHOD => {
+                let params: lsp_types::CompletionParams = serde_json::from_value(req.params)?;
+                self.completion(params.text_document_position)
+                    .map(CompletionResponse::Array)
+                    .map(serde_json::to_value)
+            }
+            HoverRequest::METHOD => {
+                let params: lsp_types::HoverParams = serde_json::from_value(req.params)?;
+                self.hover(params.text_document_position_params)
+                    .map(serde_json::to_value)
+            }
+            GotoDefinition::METHOD => {
+                let params: lsp_types::GotoDefinitionParams = serde_json::from_value(req.params)?;
+                self.definition(params.text_document_position_params)
+                    .map(serde_json::to_value)
+            }
+            References::METHOD => {
+                let params: lsp_types::ReferenceParams = serde_json::from_value(req.params)?;
+                self.references(params).map(serde_json::to_value)
+            }
+            DocumentSymbolRequest::METHOD => {
+                let params: lsp_types::DocumentSymbolParams = serde_json::from_value(req.params)?;
+                self.symbols(&params.text_document.uri)
+                    .map(serde_json::to_value)
+            }
+            _ => {
+                return self.send(Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("Unknown request '{}'", req.method),
+                ));
+            }
+        };
+        let value = result.transpose()?.unwrap_or(serde_json::Value::Null);
+        self.send(Response::new_ok(id, value))
+    }
+
+    fn document(
+        &self,
+        position: &lsp_types::TextDocumentPositionParams,
+    ) -> Option<(&Document, usize, usize)> {
+        let doc = self.documents.get(&position.text_document.uri)?;
+        let (line, col) = cursor(&doc.text, position.position);
+        Some((doc, line, col))
+    }
+
+    fn completion(
+        &self,
+        position: lsp_types::TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let (doc, line, col) = self.document(&position)?;
+        let prefix = &line_of(&doc.text, line)[..col];
+        let suggestions = analysis::complete(doc.analysis.arch, prefix, Some(&doc.labels));
+        Some(
+            suggestions
+                .into_iter()
+                .map(|s| CompletionItem {
+                    kind: Some(match s.kind {
+                        SuggestionKind::Instruction => CompletionItemKind::KEYWORD,
+                        SuggestionKind::Directive => CompletionItemKind::KEYWORD,
+                        SuggestionKind::Value => CompletionItemKind::ENUM_MEMBER,
+                        SuggestionKind::Label => CompletionItemKind::REFERENCE,
+                    }),
+                    detail: Some(s.detail),
+                    documentation: s.documentation.map(|doc| {
+                        lsp_types::Documentation::MarkupContent(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value: doc,
+                        })
+                    }),
+                    label: s.label,
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
+
+    fn hover(&self, position: lsp_types::TextDocumentPositionParams) -> Option<Hover> {
+        let (doc, line, col) = self.document(&position)?;
+        let text = analysis::hover(&doc.text, line, col, doc.analysis.arch, &doc.labels)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        })
+    }
+
+    fn definition(
+        &self,
+        position: lsp_types::TextDocumentPositionParams,
+    ) -> Option<GotoDefinitionResponse> {
+        let (doc, line, col) = self.document(&position)?;
+        let name = doc.labels.name_at(&doc.text, line, col)?;
+        let def = doc.labels.definition(name)?;
+        Some(GotoDefinitionResponse::Scalar(lsp_types::Location::new(
+            position.text_document.uri,
+            range(&doc.text, &def.location),
+        )))
+    }
+
+    fn references(&self, params: lsp_types::ReferenceParams) -> Option<Vec<lsp_types::Location>> {
+        let position = params.text_document_position;
+        let (doc, line, col) = self.document(&position)?;
+        let name = doc.labels.name_at(&doc.text, line, col)?;
+        let uri = &position.text_document.uri;
+        let def = params
+            .context
+            .include_declaration
+            .then(|| doc.labels.definition(name))
+            .flatten()
+            .map(|def| &def.location);
+        Some(
+            def.into_iter()
+                .chain(doc.labels.references(name))
+                .map(|location| lsp_types::Location::new(uri.clone(), range(&doc.text, location)))
+                .collect(),
+        )
+    }
+
+    fn symbols(&self, uri: &Url) -> Option<DocumentSymbolResponse> {
+        let doc = self.documents.get(uri)?;
+        #[allow(deprecated)]
+        fn convert(text: &str, item: Outline) -> DocumentSymbol {
+            let range = lines(text, &item.lines);
+            DocumentSymbol {
+                name: item.name,
+                detail: None,
+                kind: match item.kind {
+                    OutlineKind::Bank => SymbolKind::MODULE,
+                    OutlineKind::Org => SymbolKind::NAMESPACE,
+                    OutlineKind::Label => SymbolKind::FUNCTION,
+                },
+                tags: None,
+                deprecated: None,
+                range,
+                selection_range: lsp_types::Range::new(range.start, range.start),
+                children: Some(
+                    item.children
+                        .into_iter()
+                        .map(|child| convert(text, child))
+                        .collect(),
+                ),
+            }
+        }
+        let outline = analysis::outline(&doc.text);
+        Some(DocumentSymbolResponse::Nested(
+            outline
+                .into_iter()
+                .map(|item| convert(&doc.text, item))
+                .collect(),
+        ))
+    }
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".to_string(), "&".to_string()]),
+            ..Default::default()
+        }),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let (connection, io_threads) = Connection::stdio();
+    let params = connection.initialize(serde_json::to_value(capabilities())?)?;
+    // set by the editor using {"initializationOptions": {"arch": "nna8v2"}}
+    let default_arch = match params["initializationOptions"]["arch"].as_str() {
+        Some(name) => {
+            Architecture::from_str(name).map_err(|()| format!("Unknown architecture '{}'", name))?
+        }
+        None => Architecture::Nna8v1,
+    };
+
+    let mut server = Server {
+        connection,
+        documents: HashMap::new(),
+        default_arch,
+    };
+    while let Ok(message) = server.connection.receiver.recv() {
+        match message {
+            Message::Request(req) => {
+                if server.connection.handle_shutdown(&req)? {
+                    break;
+                }
+                server.request(req)?;
+            }
+            Message::Notification(not) => server.notification(not)?,
+            Message::Response(_) => {}
+        }
+    }
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
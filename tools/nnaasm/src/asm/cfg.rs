@@ -0,0 +1,437 @@
+//! Splits an assembled program into basic blocks connected by the ways control can flow between them.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use libnna::{
+    instruction_sets::{Nna8v1, Nna8v2},
+    Arch, Architecture, ControlFlow, Cycles, Effects, FlagEffect, OpArgType, OpArgs, Register,
+};
+
+use super::{
+    codegen::{Addr, Program, Symbol},
+    Location,
+};
+
+pub struct Op<'a> {
+    pub addr: Addr,
+    pub byte: u8,
+    pub name: &'static str,
+    pub cycles: Cycles,
+    pub effects: Effects,
+    args: Option<OpArgs>,
+    /// Where a branch or jump goes to (if it is known)
+    pub target: Option<Addr>,
+    pub location: &'a Location,
+}
+impl Op<'_> {
+    /// The instruction as it would be written in assembly
+    pub fn text(&self) -> String {
+        match self.args {
+            Some(args) => format!("{} {}", self.name, args.format_encoded(self.byte))
+                .trim_end()
+                .to_string(),
+            None => format!("{:#04x}", self.byte),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Continues with the next instruction
+    Fallthrough,
+    /// A taken `bra`, `jmp` or `mpb`
+    Branch,
+}
+
+pub struct Edge {
+    /// The block control flows to (None when it isn't known)
+    pub target: Option<usize>,
+    pub kind: EdgeKind,
+    /// The cycles the block takes when it is left using this edge
+    pub cycles: u32,
+}
+
+pub struct Block<'a> {
+    pub ops: Vec<Op<'a>>,
+    /// The labels that point to the start of the block
+    pub labels: Vec<&'a Symbol>,
+    pub edges: Vec<Edge>,
+}
+impl Block<'_> {
+    pub fn start(&self) -> Addr {
+        self.ops[0].addr
+    }
+
+    /// The address after the last instruction
+    pub fn end(&self) -> Addr {
+        let last = self.ops[self.ops.len() - 1].addr;
+        Addr {
+            bank: last.bank,
+            addr: last.addr.wrapping_add(1),
+        }
+    }
+
+    /// If a global label points to this block. (the start of a routine)
+    pub fn is_routine(&self) -> bool {
+        self.labels.iter().any(|label| label.is_global())
+    }
+}
+
+pub struct Cfg<'a> {
+    /// Blocks in source order
+    pub blocks: Vec<Block<'a>>,
+}
+
+fn decode_arch<I: Arch>(byte: u8) -> Option<(&'static str, Cycles, Effects, OpArgs)> {
+    I::decode(byte).map(|op| (op.name(), op.cycles(), op.effects(), op.args()))
+}
+
+/// The name, cycles, effects and arguments of an encoded instruction
+pub(super) fn decode(
+    arch: Architecture,
+    byte: u8,
+) -> Option<(&'static str, Cycles, Effects, OpArgs)> {
+    match arch {
+        Architecture::Nna8v1 => decode_arch::<Nna8v1>(byte),
+        Architecture::Nna8v2 => decode_arch::<Nna8v2>(byte),
+    }
+}
+
+/// The values of r0-r3 as far as they are known. (the low and high nibble are tracked separately for lil and lih)
+#[derive(Clone, Default)]
+pub(super) struct Regs([[Option<u8>; 2]; 4]);
+impl Regs {
+    pub fn get(&self, reg: u8) -> Option<u8> {
+        let [low, high] = self.0[reg as usize];
+        Some(high? << 4 | low?)
+    }
+
+    /// The low (0) or high (1) nibble of a register
+    pub fn nibble(&self, reg: u8, half: usize) -> Option<u8> {
+        self.0[reg as usize][half]
+    }
+
+    pub fn step(&mut self, name: &str, byte: u8, args: Option<OpArgs>, writes: &[Register]) {
+        let a = Register::A.gpr(byte).unwrap() as usize;
+        let b = Register::B.gpr(byte).unwrap() as usize;
+        // nna8v1 only increments by one
+        let amount = match args {
+            Some(OpArgs::ArgArg(_, arg)) if !matches!(arg.ty, OpArgType::None) => (byte & 0x03) + 1,
+            _ => 1,
+        };
+        let value = match name {
+            "inc" => self.get(a as u8).map(|v| v.wrapping_add(amount)),
+            "dec" => self.get(a as u8).map(|v| v.wrapping_sub(amount)),
+            _ => None,
+        };
+        match name {
+            "lil" => self.0[0][0] = Some(byte & 0x0F),
+            "lih" => self.0[0][1] = Some(byte & 0x0F),
+            "mov" => self.0[a] = self.0[b],
+            "xor" if a == b => self.0[a] = [Some(0); 2],
+            "inc" | "dec" => self.0[a] = [value.map(|v| v & 0x0F), value.map(|v| v >> 4)],
+            _ => self.clobber(byte, writes),
+        }
+    }
+
+    /// Forgets the registers an instruction writes
+    pub fn clobber(&mut self, byte: u8, writes: &[Register]) {
+        for reg in writes.iter().filter_map(|r| r.gpr(byte)) {
+            self.0[reg as usize] = [None; 2];
+        }
+    }
+}
+
+/// The state of the flag after executing `ops` as far as it is known
+fn flag_after(ops: &[Op]) -> Option<bool> {
+    ops.iter().fold(None, |flag, op| match op.effects.flag {
+        FlagEffect::Set => Some(true),
+        FlagEffect::Clear => Some(false),
+        FlagEffect::Flip => flag.map(|f: bool| !f),
+        FlagEffect::Write => None,
+        FlagEffect::None | FlagEffect::Consume => flag,
+    })
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        let mut ops: Vec<Op> = program
+            .instructions
+            .iter()
+            .map(|inst| {
+                let byte = program.banks[inst.bank as usize][inst.addr as usize];
+                let (name, cycles, effects, args) = match decode(program.arch, byte) {
+                    Some((name, cycles, effects, args)) => (name, cycles, effects, Some(args)),
+                    None => ("?", Cycles { base: 1, taken: 1 }, Effects::NONE, None),
+                };
+                let target = match effects.control {
+                    ControlFlow::Branch => Some(Addr {
+                        bank: inst.bank,
+                        addr: inst.addr & 0xF0 | byte & 0x0F,
+                    }),
+                    _ => None,
+                };
+                Op {
+                    addr: inst.value,
+                    byte,
+                    name,
+                    cycles,
+                    effects,
+                    args,
+                    target,
+                    location: &inst.location,
+                }
+            })
+            .collect();
+
+        let mut leaders: BTreeSet<Addr> = program.symbols.iter().map(|s| s.addr).collect();
+        leaders.extend(ops.iter().filter_map(|op| op.target));
+
+        // resolve jumps to addresses loaded using lil and lih in the same block
+        let mut regs = Regs::default();
+        for i in 0..ops.len() {
+            if i == 0 || Self::starts_block(&ops[i - 1], &ops[i], &leaders) {
+                regs = Regs::default();
+            }
+            if ops[i].effects.control == ControlFlow::Jump {
+                let reg = Register::A.gpr(ops[i].byte).unwrap();
+                let bank = ops[i].addr.bank;
+                ops[i].target = regs.get(reg).map(|addr| Addr { bank, addr });
+            }
+            regs.step(ops[i].name, ops[i].byte, ops[i].args, ops[i].effects.writes);
+        }
+        leaders.extend(ops.iter().filter_map(|op| op.target));
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for op in ops {
+            let new_block = match blocks.last() {
+                None => true,
+                Some(block) => Self::starts_block(&block.ops[block.ops.len() - 1], &op, &leaders),
+            };
+            if new_block {
+                blocks.push(Block {
+                    ops: Vec::new(),
+                    labels: Vec::new(),
+                    edges: Vec::new(),
+                });
+            }
+            blocks.last_mut().unwrap().ops.push(op);
+        }
+
+        let starts: HashMap<Addr, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.start(), i))
+            .collect();
+        for symbol in program.symbols.iter() {
+            if let Some(block) = starts.get(&symbol.addr) {
+                blocks[*block].labels.push(&symbol.value);
+            }
+        }
+
+        for block in blocks.iter_mut() {
+            let last = &block.ops[block.ops.len() - 1];
+            let base: u32 = block.ops[..block.ops.len() - 1]
+                .iter()
+                .map(|op| op.cycles.base as u32)
+                .sum();
+            let fallthrough = Edge {
+                target: starts.get(&block.end()).copied(),
+                kind: EdgeKind::Fallthrough,
+                cycles: base + last.cycles.base as u32,
+            };
+            if !Self::is_branch(last) {
+                block.edges.push(fallthrough);
+                continue;
+            }
+            // branches are taken when the flag is not set
+            let (taken, not_taken) = match last.effects.control {
+                ControlFlow::BankSwitch => (true, false),
+                _ => {
+                    let flag = flag_after(&block.ops[..block.ops.len() - 1]);
+                    (flag != Some(true), flag != Some(false))
+                }
+            };
+            if taken {
+                block.edges.push(Edge {
+                    target: last.target.and_then(|t| starts.get(&t).copied()),
+                    kind: EdgeKind::Branch,
+                    cycles: base + last.cycles.taken as u32,
+                });
+            }
+            if not_taken {
+                block.edges.push(fallthrough);
+            }
+        }
+
+        Self { blocks }
+    }
+
+    fn is_branch(op: &Op) -> bool {
+        op.effects.control != ControlFlow::Next
+    }
+
+    fn starts_block(prev: &Op, op: &Op, leaders: &BTreeSet<Addr>) -> bool {
+        Self::is_branch(prev)
+            || leaders.contains(&op.addr)
+            || prev.addr.bank != op.addr.bank
+            || prev.addr.addr.wrapping_add(1) != op.addr.addr
+    }
+
+    fn block_title(block: &Block) -> Vec<String> {
+        let (line, _) = block.ops[0].location.clone().into();
+        let mut title: Vec<String> = block
+            .labels
+            .iter()
+            .map(|label| format!("{}:", label.display_name()))
+            .collect();
+        title.push(format!("{} (line {})", block.start(), line + 1));
+        title
+    }
+
+    /// Writes the graph in the Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph cfg {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        let mut unknown = false;
+        for (i, block) in self.blocks.iter().enumerate() {
+            let lines = Self::block_title(block)
+                .into_iter()
+                .chain(block.ops.iter().map(|op| format!("  {}", op.text())));
+            let label: String = lines
+                .map(|line| format!("{}\\l", line.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            let _ = writeln!(out, "    b{} [label=\"{}\"];", i, label);
+            for edge in block.edges.iter() {
+                let target = match edge.target {
+                    Some(target) => format!("b{}", target),
+                    None => {
+                        unknown = true;
+                        "unknown".to_string()
+                    }
+                };
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "style=dashed",
+                    EdgeKind::Branch => "label=\"branch\"",
+                };
+                let _ = writeln!(out, "    b{} -> {} [{}];", i, target, style);
+            }
+        }
+        if unknown {
+            out.push_str("    unknown [shape=ellipse, label=\"?\"];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Writes the graph as JSON
+    pub fn to_json(&self) -> String {
+        let string = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+
+        let blocks = self.blocks.iter().enumerate().map(|(i, block)| {
+            let (line, _) = block.ops[0].location.clone().into();
+            format!(
+                "{{\"id\":{},\"bank\":{},\"addr\":{},\"line\":{},\"labels\":{},\"ops\":{}}}",
+                i,
+                block.start().bank,
+                block.start().addr,
+                line + 1,
+                list(
+                    block
+                        .labels
+                        .iter()
+                        .map(|l| string(l.display_name()))
+                        .collect()
+                ),
+                list(block.ops.iter().map(|op| string(&op.text())).collect()),
+            )
+        });
+        let edges = self.blocks.iter().enumerate().flat_map(|(i, block)| {
+            block.edges.iter().map(move |edge| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"kind\":\"{}\"}}",
+                    i,
+                    edge.target.map_or("null".to_string(), |t| t.to_string()),
+                    match edge.kind {
+                        EdgeKind::Fallthrough => "fallthrough",
+                        EdgeKind::Branch => "branch",
+                    }
+                )
+            })
+        });
+        format!(
+            "{{\"blocks\":{},\"edges\":{}}}",
+            list(blocks.collect()),
+            list(edges.collect())
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::{Cfg, EdgeKind};
+    use crate::asm::{assemble, Options};
+
+    #[test]
+    fn edges() {
+        let code = r#".org 0x00
+main:
+lil &func.low
+lih &func.high
+mov r3 r0
+clf
+jmp r3
+.loop:
+bra &.loop.low
+mrd r0 r0
+jmp r0
+
+func:
+nop
+"#;
+        let program = assemble(code, &Options::new(Architecture::Nna8v2)).unwrap();
+        let cfg = Cfg::new(&program);
+        let edges = |block: usize| -> Vec<(Option<usize>, EdgeKind)> {
+            cfg.blocks[block]
+                .edges
+                .iter()
+                .map(|edge| (edge.target, edge.kind))
+                .collect()
+        };
+
+        assert_eq!(cfg.blocks.len(), 4);
+        // the flag is cleared so the jump is always taken
+        assert_eq!(edges(0), vec![(Some(3), EdgeKind::Branch)]);
+        assert_eq!(
+            edges(1),
+            vec![
+                (Some(1), EdgeKind::Branch),
+                (Some(2), EdgeKind::Fallthrough)
+            ]
+        );
+        // r0 is loaded from memory so the target isn't known
+        assert_eq!(
+            edges(2),
+            vec![(None, EdgeKind::Branch), (Some(3), EdgeKind::Fallthrough)]
+        );
+        assert_eq!(cfg.blocks[3].labels[0].display_name(), "func");
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("b0 [label=\"main:\\l0x00:0x00 (line 3)\\l  lil 0x8\\l"));
+        assert!(dot.contains("b0 -> b3 [label=\"branch\"];"));
+        assert!(dot.contains("b2 -> unknown [label=\"branch\"];"));
+
+        let json = cfg.to_json();
+        assert!(json.contains(
+            r#"{"id":1,"bank":0,"addr":5,"line":9,"labels":["main.loop"],"ops":["bra 0x5"]}"#
+        ));
+        assert!(json.contains(r#"{"from":2,"to":null,"kind":"branch"}"#));
+    }
+}
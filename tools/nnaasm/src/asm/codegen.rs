@@ -1,73 +1,202 @@
-use std::{collections::HashMap, fmt::Display, ops::Range, rc::Rc};
+use std::{collections::HashMap, fmt::Display, ops::Range, path::PathBuf, rc::Rc};
+
+use libnna::Architecture;
 
 use super::{
     lex::{OpToken, RefType, Token, ValueToken8},
-    IntoAsmError, Located, Location,
+    Diagnostic, IntoAsmError, Located, Location,
 };
 pub type Bank = [u8; 256];
 
+/// The output of the code generator
+#[derive(Debug)]
+pub struct Program {
+    pub arch: Architecture,
+    pub banks: Vec<Bank>,
+    /// Every .org that was written, in source order
+    pub orgs: Vec<Org>,
+    /// Where every instruction was put, in source order
+    pub instructions: Vec<Located<Addr>>,
+    /// Where every byte that isn't an instruction (values, included bytes and padding) was put, in source order
+    pub data: Vec<Located<Addr>>,
+    pub symbols: Vec<Located<Symbol>>,
+    /// The unique names of the labels that are referenced and where
+    pub references: Vec<Located<Box<str>>>,
+    /// Warnings, lints and changes made by the optimizer
+    pub diagnostics: Vec<Diagnostic>,
+    /// The files that were included (where they were found)
+    pub includes: Vec<PathBuf>,
+}
+
+/// A location in the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Addr {
+    pub bank: u8,
+    pub addr: u8,
+}
+impl Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#04x}:{:#04x}", self.bank, self.addr)
+    }
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    /// The unique name of the label (local and anonymous labels are mangled)
+    pub name: Box<str>,
+    pub addr: Addr,
+}
+impl Symbol {
+    /// The name as it was written in the source
+    pub fn display_name(&self) -> &str {
+        LabelScope::display_name(&self.name)
+    }
+
+    /// If the label isn't local, anonymous or generated by the assembler
+    pub fn is_global(&self) -> bool {
+        !self.name.contains(['.', '#'])
+    }
+}
+
+#[derive(Debug)]
 pub enum CodeGenError {
     NoOrg(),
     OrgOverlap(Org, Org),
     OrgOutOfBounds(usize),
+    /// A `.fill`, `.space` or `.align` doesn't fit in the bank. (the bytes it adds and how many are left)
+    FillOutOfBounds(usize, usize),
     LabelNotDefined(Box<str>),
+    /// A label is defined twice. (contains where it was first defined)
+    LabelRedefined(Box<str>, Location),
+    LocalLabelWithoutScope(Box<str>),
     ReachableAssertionFailed,
+    /// The optimizer moved a branch onto another page than its label. (contains where the label is defined)
+    BranchOffPage(Box<str>, Location),
 }
 impl IntoAsmError for Located<CodeGenError> {
     fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> super::AsmError<'a> {
+        let mut notes = Vec::new();
         let message = match self.value {
             CodeGenError::NoOrg() => {
                 "Everything needs to be defined inside an .org statement. Otherwise the assembler can't know where to put it in the final output binary".to_string()
             }
             CodeGenError::LabelNotDefined(name) => format!("label '{}' is not defined", name),
+            CodeGenError::LabelRedefined(name, first) => {
+                notes.push(Located::new("first defined here".to_string(), first));
+                format!("label '{}' is already defined", name)
+            }
+            CodeGenError::LocalLabelWithoutScope(name) => {
+                format!("local label '{}' needs a global label before it", name)
+            }
             CodeGenError::OrgOverlap(org0, org1) => {
                 format!("This org ({}) overlaps with: {}", org0, org1)
             },
             CodeGenError::ReachableAssertionFailed => {
-                format!("Address is not reachable from here.")
+                "Address is not reachable from here.".to_string()
+            }
+            CodeGenError::BranchOffPage(name, label) => {
+                notes.push(Located::new("label defined here".to_string(), label));
+                format!("After optimizing, '{}' is on another page than this branch so it can't be reached", name)
             }
             CodeGenError::OrgOutOfBounds(size) => {
                 format!("This org (size: {:#04x}) extends past the bounds of the bank it is in.", size)
             }
+            CodeGenError::FillOutOfBounds(count, left) => {
+                format!("This adds {:#04x} bytes but only {:#04x} are left in the bank.", count, left)
+            }
         };
-        super::AsmError {
-            filename,
-            code,
-            message,
-            location: self.location,
-        }
+        let mut error = super::AsmError::new(filename, code, self.location, message);
+        error.notes = notes;
+        error
     }
 }
 
-pub fn calc_mem_usage(data: &Vec<Bank>, mem_size: u16) -> Vec<Range<usize>> {
-    let mut banks = Vec::new();
-    let mut lefover_size = mem_size as usize;
-
-    for bank in data {
-        let bank_size = if lefover_size < size_of::<Bank>() {
-            let bank_size = size_of::<Bank>() - lefover_size;
-            lefover_size = 0;
-            bank_size
-        } else {
-            lefover_size -= size_of::<Bank>();
-            size_of::<Bank>()
+#[derive(Debug)]
+pub enum CodeGenWarning {
+    /// An org overwrites non-zero bytes of the base image. (contains the number of bytes)
+    BaseOverwritten(Org, usize),
+    /// A local label has the name of a global label. (contains where the global label is defined)
+    LocalShadowsGlobal(Box<str>, Location),
+}
+impl IntoAsmError for Located<CodeGenWarning> {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> super::AsmError<'a> {
+        let mut notes = Vec::new();
+        let message = match self.value {
+            CodeGenWarning::BaseOverwritten(org, count) => {
+                format!(
+                    "This org ({}) overwrites {} non-zero byte(s) of the base image",
+                    org, count
+                )
+            }
+            CodeGenWarning::LocalShadowsGlobal(name, global) => {
+                notes.push(Located::new(
+                    "global label defined here".to_string(),
+                    global,
+                ));
+                format!(
+                    "local label '{}' has the name of the global label '{}'",
+                    name,
+                    &name[1..]
+                )
+            }
         };
+        let mut warning = super::AsmError::warning(filename, code, self.location, message);
+        warning.notes = notes;
+        warning
+    }
+}
 
-        banks.push(calc_bank_usage(bank, bank_size));
+/// How much of a bank is used by orgs
+pub struct BankUsage {
+    pub bank: u8,
+    /// How many bytes of the bank are memory (the rest is mapped to peripherals)
+    pub size: usize,
+    /// The orgs in this bank sorted by address
+    pub orgs: Vec<Org>,
+    /// Address ranges no org writes to
+    pub free: Vec<Range<usize>>,
+}
+impl BankUsage {
+    pub fn used(&self) -> usize {
+        self.orgs.iter().map(|org| org.size as usize).sum()
+    }
+
+    pub fn largest_free(&self) -> Option<Range<usize>> {
+        self.free.iter().max_by_key(|free| free.len()).cloned()
     }
-    banks
 }
 
-fn calc_bank_usage(bank: &Bank, usable_size: usize) -> Range<usize> {
-    let mut zero_bytes = 0;
-    for byte in &bank[..usable_size] {
-        if *byte != 0 {
-            zero_bytes = 0;
-            continue;
+/// Calculates the usage of every bank that contains at least one org.
+pub fn calc_mem_usage(orgs: &[Org], arch: Architecture) -> Vec<BankUsage> {
+    let mut orgs = orgs.to_vec();
+    orgs.sort_by_key(|org| (org.bank, org.start_addr));
+
+    let mut banks: Vec<BankUsage> = Vec::new();
+    for org in orgs {
+        match banks.last_mut() {
+            Some(usage) if usage.bank == org.bank => usage.orgs.push(org),
+            _ => banks.push(BankUsage {
+                bank: org.bank,
+                size: arch.memory_range(org.bank).len(),
+                orgs: vec![org],
+                free: Vec::new(),
+            }),
+        }
+    }
+
+    for usage in banks.iter_mut() {
+        let mut addr = 0;
+        for org in usage.orgs.iter().filter(|org| org.size > 0) {
+            if org.start_addr as usize > addr {
+                usage.free.push(addr..org.start_addr as usize);
+            }
+            addr = org.end_addr() as usize;
+        }
+        if addr < usage.size {
+            usage.free.push(addr..usage.size);
         }
-        zero_bytes += 1;
     }
-    (usable_size - zero_bytes)..usable_size
+    banks
 }
 
 // fn emit_hex(data: [u4; 256]) -> Vec<u8> {
@@ -98,8 +227,55 @@ impl OrgBuilder {
         }
     }
 
+    /// The address the next byte will be written to
+    pub fn addr(&self) -> usize {
+        self.start_addr as usize + self.data.len()
+    }
+
+    /// Where the next byte will be put in the output
+    pub fn placed(&self) -> Addr {
+        Addr {
+            bank: self.bank,
+            addr: self.addr() as u8,
+        }
+    }
+
+    /// Append `count` bytes with the given value (if they fit in the bank)
+    pub fn fill(&mut self, count: usize, value: u8) -> Result<(), CodeGenError> {
+        let left = std::mem::size_of::<Bank>().saturating_sub(self.addr());
+        if count > left {
+            return Err(CodeGenError::FillOutOfBounds(count, left));
+        }
+        self.data.resize(self.data.len() + count, value);
+        Ok(())
+    }
+
+    /// Where the bytes from `start` on were put (to record the data added since then)
+    fn placed_since(&self, start: usize, location: &Location) -> Vec<Located<Addr>> {
+        (start..self.addr())
+            .map(|addr| {
+                let addr = Addr {
+                    bank: self.bank,
+                    addr: addr as u8,
+                };
+                Located::new(addr, location.clone())
+            })
+            .collect()
+    }
+
+    /// Pad with zeros until the next address is a multiple of `alignment`
+    pub fn align(&mut self, alignment: usize) -> Result<(), CodeGenError> {
+        let addr = self.addr();
+        self.fill(addr.next_multiple_of(alignment) - addr, 0)
+    }
+
     #[inline]
-    pub fn write(&mut self, bin: &mut Bank, orgs: &[Org]) -> Result<Org, Located<CodeGenError>> {
+    pub fn write(
+        &mut self,
+        bin: &mut Bank,
+        orgs: &[Org],
+        warnings: &mut Vec<Located<CodeGenWarning>>,
+    ) -> Result<Org, Located<CodeGenError>> {
         if self.start_addr as usize + self.data.len() >= bin.len() {
             return Err(Located::new(
                 CodeGenError::OrgOutOfBounds(self.data.len()),
@@ -125,18 +301,26 @@ impl OrgBuilder {
             }
         }
 
-        for (i, nib) in self.data.iter().enumerate() {
-            bin[org.start_addr as usize + i] = *nib;
+        // orgs can't overlap so everything in this range came from the base image
+        let region = &mut bin[org.start_addr as usize..][..self.data.len()];
+        let overwritten = region.iter().filter(|b| **b != 0).count();
+        if overwritten > 0 {
+            warnings.push(Located::new(
+                CodeGenWarning::BaseOverwritten(org, overwritten),
+                self.loc.clone(),
+            ));
         }
+
+        region.copy_from_slice(&self.data);
         Ok(org)
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Org {
-    start_addr: u8,
-    bank: u8,
-    size: u8,
+    pub start_addr: u8,
+    pub bank: u8,
+    pub size: u8,
 }
 impl Display for Org {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -152,26 +336,82 @@ impl Org {
     }
 
     pub fn overlap(self, other: Org) -> bool {
-        if other.start_addr < self.start_addr && other.end_addr() < self.start_addr {
-            false
-        } else if other.start_addr > self.end_addr() {
-            false
-        } else {
-            true
-        }
+        self.start_addr < other.end_addr() && other.start_addr < self.end_addr()
     }
 }
 
 #[inline]
 fn resolve_label(
-    labels: &HashMap<Box<str>, u8>,
+    labels: &HashMap<Box<str>, Located<u8>>,
     label: Located<(Box<str>, RefType)>,
 ) -> Result<u8, Located<CodeGenError>> {
     let resolved_label_addr = labels.get(&label.value.0).ok_or_else(|| {
-        Located::new(CodeGenError::LabelNotDefined(label.value.0), label.location)
+        let name = LabelScope::display_name(&label.value.0).into();
+        Located::new(CodeGenError::LabelNotDefined(name), label.location)
     })?;
 
-    Ok(label.value.1.mask_low(*resolved_label_addr))
+    Ok(label.value.1.mask_low(resolved_label_addr.value))
+}
+
+/// Turns local (`.name`) and anonymous (`+`/`-`) labels into unique names.
+#[derive(Default)]
+struct LabelScope {
+    /// The last defined global label. Local labels are attached to it.
+    global: Option<Box<str>>,
+    /// Amount of `+` labels defined so far
+    forward: usize,
+    /// Amount of `-` labels defined so far
+    backward: usize,
+}
+impl LabelScope {
+    fn local(&self, name: &str) -> Result<Box<str>, CodeGenError> {
+        match &self.global {
+            Some(global) => Ok(format!("{}{}", global, name).into()),
+            None => Err(CodeGenError::LocalLabelWithoutScope(name.into())),
+        }
+    }
+
+    /// The unique name of a label definition
+    fn define(&mut self, name: Box<str>) -> Result<Box<str>, CodeGenError> {
+        match &*name {
+            "+" => {
+                self.forward += 1;
+                Ok(format!("+#{}", self.forward - 1).into())
+            }
+            "-" => {
+                self.backward += 1;
+                Ok(format!("-#{}", self.backward - 1).into())
+            }
+            _ if name.starts_with('.') => self.local(&name),
+            // generated by the assembler (for example `ret#print` or `call#0`) so already unique
+            _ if name.contains(['.', '#']) => Ok(name),
+            _ => {
+                self.global = Some(name.clone());
+                Ok(name)
+            }
+        }
+    }
+
+    /// The unique name of the label a reference points to
+    fn reference(&self, name: Box<str>) -> Result<Box<str>, CodeGenError> {
+        if name.starts_with('+') {
+            Ok(format!("+#{}", self.forward + name.len() - 1).into())
+        } else if name.starts_with('-') {
+            match self.backward.checked_sub(name.len()) {
+                Some(index) => Ok(format!("-#{}", index).into()),
+                None => Err(CodeGenError::LabelNotDefined(name)),
+            }
+        } else if name.starts_with('.') {
+            self.local(&name)
+        } else {
+            Ok(name)
+        }
+    }
+
+    /// The name of a label as it was written in the source
+    fn display_name(name: &str) -> &str {
+        name.split_once('#').map_or(name, |(sign, _)| sign)
+    }
 }
 
 struct LabelRef {
@@ -189,7 +429,7 @@ fn alloc_bank(mem: &mut Vec<Bank>, bank: u8) -> &mut Bank {
 
 fn resolve_labels(
     mem: &mut Vec<Bank>,
-    labels: HashMap<Box<str>, u8>,
+    labels: HashMap<Box<str>, Located<u8>>,
     mut label_refs: Vec<Located<LabelRef>>,
 ) -> Result<(), Located<CodeGenError>> {
     for lref in label_refs.drain(..) {
@@ -207,11 +447,23 @@ fn resolve_labels(
     Ok(())
 }
 
-pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>> {
-    let mut mem = Vec::new();
+/// Generates the program on top of the `base` image. (pass an empty Vec to start from zeroed banks)
+pub fn gen(
+    tt: Vec<Located<Token>>,
+    mut arch: Architecture,
+    base: Vec<Bank>,
+) -> Result<Program, Located<CodeGenError>> {
+    let mut mem = base;
+    let mut warnings = Vec::new();
+    let mut instructions = Vec::new();
+    let mut data = Vec::new();
+    let mut symbols = Vec::new();
     let mut label_refs = Vec::new();
     let mut reachable_checks = Vec::new();
-    let mut labels = HashMap::new();
+    let mut labels: HashMap<Box<str>, Located<u8>> = HashMap::new();
+    // the local labels as they were written (checked against the global labels at the end)
+    let mut locals: Vec<Located<Box<str>>> = Vec::new();
+    let mut label_scope = LabelScope::default();
 
     let mut cur_bank_num = 0;
     let mut cur_bank = alloc_bank(&mut mem, 0);
@@ -223,8 +475,8 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
         loc: Location,
     ) -> Result<&mut OrgBuilder, Located<CodeGenError>> {
         match org {
-            Some(org) => return Ok(org),
-            None => return Err(Located::new(CodeGenError::NoOrg(), loc)),
+            Some(org) => Ok(org),
+            None => Err(Located::new(CodeGenError::NoOrg(), loc)),
         }
     }
 
@@ -232,10 +484,15 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
         match token.value {
             Token::Op(OpToken::Full(byte)) => {
                 let org = org(&mut cur_org, token.location.clone())?;
+                instructions.push(Located::new(org.placed(), token.location));
                 org.data.push(byte);
             }
             Token::Op(OpToken::LabelRef(instruct, label, ref_type)) => {
                 let org = org(&mut cur_org, token.location.clone())?;
+                instructions.push(Located::new(org.placed(), token.location.clone()));
+                let label = label_scope
+                    .reference(label)
+                    .map_err(|e| Located::new(e, token.location.clone()))?;
                 label_refs.push(Located::new(
                     LabelRef {
                         label,
@@ -248,11 +505,16 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
                 org.data.push(instruct);
             }
             Token::Value(ValueToken8::Const(value)) => {
-                let org = org(&mut cur_org, token.location)?;
+                let org = org(&mut cur_org, token.location.clone())?;
+                data.push(Located::new(org.placed(), token.location));
                 org.data.push(value);
             }
             Token::Value(ValueToken8::LabelRef(label, ref_type)) => {
                 let org = org(&mut cur_org, token.location.clone())?;
+                data.push(Located::new(org.placed(), token.location.clone()));
+                let label = label_scope
+                    .reference(label)
+                    .map_err(|e| Located::new(e, token.location.clone()))?;
                 label_refs.push(Located::new(
                     LabelRef {
                         label,
@@ -266,17 +528,38 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
             }
             Token::LabelDef(name) => {
                 let org = org(&mut cur_org, token.location.clone())?;
-                labels.insert(name, org.start_addr + org.data.len() as u8);
+                if name.starts_with('.') {
+                    locals.push(Located::new(name.clone(), token.location.clone()));
+                }
+                let name = label_scope
+                    .define(name)
+                    .map_err(|e| Located::new(e, token.location.clone()))?;
+                if let Some(first) = labels.get(&name) {
+                    let name = LabelScope::display_name(&name).into();
+                    return Err(Located::new(
+                        CodeGenError::LabelRedefined(name, first.location.clone()),
+                        token.location,
+                    ));
+                }
+                let addr = org.start_addr + org.data.len() as u8;
+                symbols.push(Located::new(
+                    Symbol {
+                        name: name.clone(),
+                        addr: org.placed(),
+                    },
+                    token.location.clone(),
+                ));
+                labels.insert(name, Located::new(addr, token.location));
             }
             Token::Org(addr) => {
                 if let Some(org) = &mut cur_org {
-                    orgs.push(org.write(&mut cur_bank, &orgs)?);
+                    orgs.push(org.write(cur_bank, &orgs, &mut warnings)?);
                 }
                 cur_org = Some(OrgBuilder::new(token.location, cur_bank_num, addr));
             }
             Token::Bank(addr) => {
                 if let Some(org) = &mut cur_org {
-                    orgs.push(org.write(&mut cur_bank, &orgs)?);
+                    orgs.push(org.write(cur_bank, &orgs, &mut warnings)?);
                 }
                 cur_org = None;
                 cur_bank_num = addr;
@@ -291,24 +574,48 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
                 match start {
                     ValueToken8::Const(start) => check_reachable(end, start)?,
                     ValueToken8::LabelRef(label, ref_type) => {
+                        let label = label_scope
+                            .reference(label)
+                            .map_err(|e| Located::new(e, end.location.clone()))?;
                         reachable_checks.push((end, label, ref_type));
                     }
                 }
             }
+            Token::Align(alignment) => {
+                let org = org(&mut cur_org, token.location.clone())?;
+                let start = org.addr();
+                org.align(alignment as usize)
+                    .map_err(|e| Located::new(e, token.location.clone()))?;
+                data.extend(org.placed_since(start, &token.location));
+            }
+            Token::Fill { count, value } => {
+                let org = org(&mut cur_org, token.location.clone())?;
+                let start = org.addr();
+                org.fill(count as usize, value)
+                    .map_err(|e| Located::new(e, token.location.clone()))?;
+                data.extend(org.placed_since(start, &token.location));
+            }
             Token::Bytes(bytes) => {
-                let org = org(&mut cur_org, token.location)?;
+                let org = org(&mut cur_org, token.location.clone())?;
+                let start = org.addr();
                 org.data.extend_from_slice(&bytes);
+                data.extend(org.placed_since(start, &token.location));
             }
             Token::IncludeBytes(_) => {}
-            Token::Arch(_) => {}
+            Token::Arch(token_arch) => arch = token_arch,
         }
     }
     //write last org
     if let Some(org) = &mut cur_org {
-        org.write(&mut cur_bank, &orgs)?;
+        orgs.push(org.write(cur_bank, &orgs, &mut warnings)?);
     }
 
+    let mut references: Vec<Located<Box<str>>> = label_refs
+        .iter()
+        .map(|lref| Located::new(lref.value.label.clone(), lref.location.clone()))
+        .collect();
     for (end, label, ref_type) in reachable_checks.drain(..) {
+        references.push(Located::new(label.clone(), end.location.clone()));
         let resolved = resolve_label(
             &labels,
             Located::new((label, ref_type), end.location.clone()),
@@ -316,9 +623,28 @@ pub fn gen(tt: Vec<Located<Token>>) -> Result<Vec<Bank>, Located<CodeGenError>>
         check_reachable(end, resolved)?;
     }
 
+    for local in locals {
+        if let Some(global) = labels.get(&local.value[1..]) {
+            warnings.push(Located::new(
+                CodeGenWarning::LocalShadowsGlobal(local.value, global.location.clone()),
+                local.location,
+            ));
+        }
+    }
+
     resolve_labels(&mut mem, labels, label_refs)?;
 
-    Ok(mem)
+    Ok(Program {
+        arch,
+        banks: mem,
+        orgs,
+        instructions,
+        data,
+        symbols,
+        references,
+        diagnostics: warnings.into_iter().map(Diagnostic::CodeGen).collect(),
+        includes: Vec::new(),
+    })
 }
 
 fn check_reachable(end: Located<u8>, start: u8) -> Result<(), Located<CodeGenError>> {
@@ -337,6 +663,33 @@ fn check_reachable(end: Located<u8>, start: u8) -> Result<(), Located<CodeGenErr
 #[cfg(test)]
 mod test {
 
+    #[test]
+    fn mem_usage() {
+        use super::{calc_mem_usage, Architecture, Org};
+        let org = |bank, start_addr, size| Org {
+            start_addr,
+            bank,
+            size,
+        };
+        let orgs = [org(0, 0x20, 0x10), org(2, 0x00, 0x04), org(0, 0x00, 0x08)];
+        let usage = calc_mem_usage(&orgs, Architecture::Nna8v2);
+        assert_eq!(usage.len(), 2);
+
+        assert_eq!(usage[0].bank, 0);
+        assert_eq!(usage[0].used(), 0x18);
+        assert_eq!(usage[0].orgs[0].start_addr, 0x00);
+        assert_eq!(usage[0].free, vec![0x08..0x20, 0x30..0x100]);
+        assert_eq!(usage[0].largest_free(), Some(0x30..0x100));
+
+        assert_eq!(usage[1].bank, 2);
+        assert_eq!(usage[1].free, vec![0x04..0x100]);
+
+        // the end of the bank is mapped to the keyboard and screen
+        let usage = calc_mem_usage(&orgs, Architecture::Nna8v1);
+        assert_eq!(usage[0].size, 0xEE);
+        assert_eq!(usage[0].free, vec![0x08..0x20, 0x30..0xEE]);
+    }
+
     #[test]
     fn alloc_bank() {
         let mut mem = Vec::new();
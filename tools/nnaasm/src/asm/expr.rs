@@ -0,0 +1,195 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use libnna::{ParseBin, ParseHex};
+
+/// Named constants that can be used inside expressions (for example the counter of a `.rept` block)
+pub type Symbols = HashMap<Box<str>, u64>;
+
+type Result<T> = std::result::Result<T, Cow<'static, str>>;
+
+/// Evaluates a constant expression like `(i*0x4+0x10)`.
+///
+/// Expressions can't contain whitespace because the parser splits tokens on it.
+/// Supported operators (from low to high precedence): `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/` `%`
+/// and the unary `-` and `~`.
+pub fn eval(expr: &str, symbols: &Symbols) -> Result<u64> {
+    let mut eval = Eval {
+        expr,
+        pos: 0,
+        symbols,
+    };
+    let value = eval.or()?;
+    if eval.pos != expr.len() {
+        return Err(format!("Unexpected '{}' in expression", &expr[eval.pos..]).into());
+    }
+    Ok(value)
+}
+
+struct Eval<'a> {
+    expr: &'a str,
+    pos: usize,
+    symbols: &'a Symbols,
+}
+impl<'a> Eval<'a> {
+    fn rest(&self) -> &'a str {
+        &self.expr[self.pos..]
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if self.rest().starts_with(op) {
+            self.pos += op.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<u64> {
+        let mut value = self.xor()?;
+        while self.eat("|") {
+            value |= self.xor()?;
+        }
+        Ok(value)
+    }
+
+    fn xor(&mut self) -> Result<u64> {
+        let mut value = self.and()?;
+        while self.eat("^") {
+            value ^= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<u64> {
+        let mut value = self.shift()?;
+        while self.eat("&") {
+            value &= self.shift()?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<u64> {
+        let mut value = self.sum()?;
+        loop {
+            if self.eat("<<") {
+                value = value.checked_shl(self.sum()? as u32).unwrap_or(0);
+            } else if self.eat(">>") {
+                value = value.checked_shr(self.sum()? as u32).unwrap_or(0);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sum(&mut self) -> Result<u64> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat("+") {
+                value = value.wrapping_add(self.product()?);
+            } else if self.eat("-") {
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<u64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat("*") {
+                value = value.wrapping_mul(self.unary()?);
+            } else if self.eat("/") {
+                value = value
+                    .checked_div(self.unary()?)
+                    .ok_or(Cow::Borrowed("Division by zero in expression"))?;
+            } else if self.eat("%") {
+                value = value
+                    .checked_rem(self.unary()?)
+                    .ok_or(Cow::Borrowed("Division by zero in expression"))?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<u64> {
+        if self.eat("-") {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.eat("~") {
+            return Ok(!self.unary()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<u64> {
+        if self.eat("(") {
+            let value = self.or()?;
+            if !self.eat(")") {
+                return Err(Cow::Borrowed("Missing ')' in expression"));
+            }
+            return Ok(value);
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(self.rest().len());
+        let word = &self.rest()[..len];
+        self.pos += len;
+
+        if word.is_empty() {
+            return Err(Cow::Borrowed("Expected a value in expression"));
+        }
+        if let Some(hex) = word.strip_prefix("0x") {
+            return u64::parse_hex(hex)
+                .ok_or(format!("Invalid hex literal '{}' in expression", word).into());
+        }
+        if let Some(bin) = word.strip_prefix("0b") {
+            return u64::parse_bin(bin)
+                .ok_or(format!("Invalid binary literal '{}' in expression", word).into());
+        }
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return word
+                .parse()
+                .map_err(|_| format!("Invalid decimal literal '{}' in expression", word).into());
+        }
+        self.symbols
+            .get(word)
+            .copied()
+            .ok_or(format!("Unknown symbol '{}' in expression", word).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{eval, Symbols};
+
+    #[test]
+    fn precedence() {
+        let symbols = Symbols::new();
+        assert_eq!(eval("(1+2*3)", &symbols), Ok(7));
+        assert_eq!(eval("((1+2)*3)", &symbols), Ok(9));
+        assert_eq!(eval("(0x10|1<<2)", &symbols), Ok(0x14));
+        assert_eq!(eval("(0b1010&0xF-0x3)", &symbols), Ok(0b1000));
+        assert_eq!(eval("(-1&0xFF)", &symbols), Ok(0xFF));
+    }
+
+    #[test]
+    fn symbols() {
+        let mut symbols = Symbols::new();
+        symbols.insert("i".into(), 3);
+        assert_eq!(eval("(i*0x10+i)", &symbols), Ok(0x33));
+        assert!(eval("(j)", &symbols).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let symbols = Symbols::new();
+        assert!(eval("(1+)", &symbols).is_err());
+        assert!(eval("(1/0)", &symbols).is_err());
+        assert!(eval("(1", &symbols).is_err());
+        assert!(eval("(1))", &symbols).is_err());
+    }
+}
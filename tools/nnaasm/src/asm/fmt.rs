@@ -0,0 +1,274 @@
+//! Formats sources in a consistent style without changing what they assemble to
+
+use std::ops::Range;
+
+use super::{assemble, parse::Parser, Bank, IntoAsmError, Options};
+
+const INDENT: &str = "    ";
+
+/// Directives that start a new section and aren't indented
+const SECTIONS: &[&str] = &[".org", ".bank", ".arch", ".func", ".endfunc"];
+
+struct Line<'a> {
+    tokens: Vec<&'a str>,
+    comment: Option<&'a str>,
+}
+impl Line<'_> {
+    fn code(&self, indented: bool) -> String {
+        let code = self.tokens.join(" ");
+        if indented && !code.is_empty() {
+            format!("{}{}", INDENT, code)
+        } else {
+            code
+        }
+    }
+}
+
+/// Splits the source into the tokens (as the assembler sees them) and comment of every line
+fn lines(code: &str) -> Vec<Line<'_>> {
+    let mut lines: Vec<Line> = code
+        .lines()
+        .map(|_| Line {
+            tokens: Vec::new(),
+            comment: None,
+        })
+        .collect();
+    let mut ends = vec![0; lines.len()];
+    if let Some(mut parser) = Parser::new(code) {
+        while let Some(token) = parser.next() {
+            let (line, cols): (usize, Range<usize>) = parser.location().into();
+            if let Some(out) = lines.get_mut(line) {
+                out.tokens.push(token);
+                ends[line] = cols.end;
+            }
+        }
+    }
+    for ((line, source), end) in lines.iter_mut().zip(code.lines()).zip(ends) {
+        let rest = source.get(end..).unwrap_or_default();
+        line.comment = rest
+            .find(';')
+            .map(|start| rest[start..].trim_end())
+            .filter(|_| !line.tokens.last().is_some_and(|token| token.contains(';')));
+    }
+    lines.into_iter().flat_map(split_labels).collect()
+}
+
+/// Puts every label on its own line so the code after it is indented like the rest (the comment stays with the last part)
+fn split_labels(line: Line<'_>) -> Vec<Line<'_>> {
+    let mut out: Vec<Line> = Vec::new();
+    for token in line.tokens {
+        let after_label = out
+            .last()
+            .is_none_or(|last| last.tokens.last().is_some_and(|last| is_label(last)));
+        if after_label || is_label(token) {
+            out.push(Line {
+                tokens: Vec::new(),
+                comment: None,
+            });
+        }
+        out.last_mut().unwrap().tokens.push(token);
+    }
+    match out.last_mut() {
+        Some(last) => last.comment = line.comment,
+        None => out.push(Line {
+            tokens: Vec::new(),
+            comment: line.comment,
+        }),
+    }
+    out
+}
+
+fn is_label(token: &str) -> bool {
+    token.ends_with(':') && !token.starts_with('"')
+}
+
+/// Formats a source:
+/// - tokens are separated by a single space
+/// - labels and section directives (`.org`, `.bank`, `.arch`, `.func`) start at the beginning of the line
+///   and the code under a label is indented (labels get their own line)
+/// - comments after code are aligned within a paragraph and comment lines are indented like the next line
+/// - blank lines are kept
+pub fn format(code: &str) -> String {
+    let lines = lines(code);
+
+    // if every line with code is indented
+    let mut indented = vec![false; lines.len()];
+    let mut under_label = false;
+    for (line, indent) in lines.iter().zip(indented.iter_mut()) {
+        let Some(first) = line.tokens.first() else {
+            continue;
+        };
+        if is_label(first) {
+            under_label = true;
+        } else if SECTIONS.contains(first) {
+            under_label = *first == ".func";
+        } else {
+            *indent = under_label;
+        }
+    }
+    // comment lines belong to the code after them
+    let mut next = false;
+    for (line, indent) in lines.iter().zip(indented.iter_mut()).rev() {
+        match (line.tokens.is_empty(), line.comment.is_some()) {
+            (false, _) => next = *indent,
+            (true, true) => *indent = next,
+            (true, false) => {}
+        }
+    }
+
+    let mut out = String::new();
+    let mut start = 0;
+    while start < lines.len() {
+        // a paragraph is a run of lines that aren't blank
+        let blank = |line: &Line| line.tokens.is_empty() && line.comment.is_none();
+        if blank(&lines[start]) {
+            out.push('\n');
+            start += 1;
+            continue;
+        }
+        let end = (start..lines.len())
+            .find(|i| blank(&lines[*i]))
+            .unwrap_or(lines.len());
+        let paragraph = start..end;
+        let column = paragraph
+            .clone()
+            .filter(|i| !lines[*i].tokens.is_empty() && lines[*i].comment.is_some())
+            .map(|i| lines[i].code(indented[i]).len())
+            .max()
+            .unwrap_or(0);
+        for i in paragraph {
+            let line = &lines[i];
+            let code = line.code(indented[i]);
+            match (code.is_empty(), line.comment) {
+                (_, None) => out.push_str(&code),
+                (true, Some(comment)) => {
+                    if indented[i] {
+                        out.push_str(INDENT);
+                    }
+                    out.push_str(comment);
+                }
+                (false, Some(comment)) => {
+                    out.push_str(&format!("{:width$} {}", code, comment, width = column))
+                }
+            }
+            out.push('\n');
+        }
+        start = end;
+    }
+    // a single newline at the end of the file
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    out
+}
+
+/// What a source assembles to or the line and message of the error
+fn output(code: &str, options: &Options) -> Result<Vec<Bank>, (usize, String)> {
+    assemble(code, options)
+        .map(|program| program.banks)
+        .map_err(|err| {
+            let err = err.into_asm_error(code, "".into());
+            let (line, _) = err.location.into();
+            (line, err.message)
+        })
+}
+
+/// If the formatted source assembles to the same image as the original.
+/// Fails with the line and message of the error when the original doesn't assemble. (so there is nothing to compare)
+pub fn same_output(
+    code: &str,
+    formatted: &str,
+    options: &Options,
+) -> Result<bool, (usize, String)> {
+    let original = output(code, options)?;
+    Ok(output(formatted, options).is_ok_and(|banks| banks == original))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path, str::FromStr};
+
+    use libnna::Architecture;
+
+    use super::{format, same_output};
+    use crate::asm::Options;
+
+    #[test]
+    fn format_style() {
+        let code = "; demo\n.org   0x00\nstart:\n\tlil\t&start.low  ; low\nmov r1 r0 ;   r1 = r0\n  ; next\n.loop:   bra &.loop.low\n\n\n\n.org 0x10\nxor r0 r0\n\n";
+        assert_eq!(
+            format(code),
+            "; demo
+.org 0x00
+start:
+    lil &start.low ; low
+    mov r1 r0      ;   r1 = r0
+; next
+.loop:
+    bra &.loop.low
+
+
+
+.org 0x10
+xor r0 r0
+"
+        );
+        let formatted = format(code);
+        assert_eq!(format(&formatted), formatted);
+    }
+
+    #[test]
+    fn compare_output() {
+        let options = Options::new(Architecture::Nna8v2);
+        let code = ".org 0x00\ninc r1 0x1\n";
+        assert_eq!(same_output(code, &format(code), &options), Ok(true));
+        assert_eq!(
+            same_output(code, ".org 0x00\ninc r1 0x2\n", &options),
+            Ok(false)
+        );
+        // an error can't be compared even if the formatted source has the same one
+        let code = ".org 0x00\nfoo r1\n";
+        assert!(same_output(code, code, &options).is_err());
+    }
+
+    /// Examples that don't assemble with this assembler (so formatting them can't be checked)
+    const BROKEN_EXAMPLES: &[&str] = &[
+        "nna8v1/programs/keyboard_test.asm",
+        "nna8v1/programs/pong.asm",
+    ];
+
+    #[test]
+    fn example_programs() {
+        let hw = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../hw");
+        for name in ["nna8v1", "nna8v2", "nna8v3"] {
+            // the assembler doesn't support every architecture there are programs for
+            let Ok(arch) = Architecture::from_str(name) else {
+                continue;
+            };
+            let dir = hw.join(name).join("programs");
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry.unwrap().path();
+                if path.extension().is_none_or(|ext| ext != "asm") {
+                    continue;
+                }
+                let code = fs::read_to_string(&path).unwrap();
+                let formatted = format(&code);
+                let options = Options {
+                    path: Some(path.clone()),
+                    ..Options::new(arch)
+                };
+                let example = path.strip_prefix(&hw).unwrap().to_str().unwrap();
+                let same = same_output(&code, &formatted, &options);
+                if BROKEN_EXAMPLES.contains(&example) {
+                    assert!(same.is_err(), "{} assembles now", example);
+                } else {
+                    assert_eq!(same, Ok(true), "{}", example);
+                }
+                assert_eq!(format(&formatted), formatted, "{:?}", path);
+            }
+        }
+    }
+}
@@ -2,15 +2,81 @@ use std::borrow::Cow;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use super::cfg::Regs;
+use super::expr::{self, Symbols};
 use super::parse::Parser;
+use super::pseudo::{self, Func, Stack};
+use super::suggest;
 use super::{IntoAsmError, Located, Location};
-use libnna::instruction_sets::{Nna8v1, Nna8v2};
+use libnna::instruction_sets::{CalOp, Nna8v1, Nna8v2, Reg};
 use libnna::{
-    u2, u4, Arch, Architecture, ConstArg, MaxValue, OpArg, OpArgType, OpArgs, ParseBin, ParseHex,
+    u2, u4, Arch, Architecture, ConstArg, ConstOpArg, MaxValue, OpArg, OpArgType, OpArgs, ParseBin,
+    ParseHex,
 };
 
 type Result<T> = std::result::Result<Located<T>, Located<LexError>>;
 
+/// A directive of the assembler (`.org`, `.rept`, ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Directive {
+    Org,
+    Bank,
+    Reachable,
+    Align,
+    Fill,
+    Space,
+    IncludeBytes,
+    Arch,
+    Rept,
+    Endr,
+    Func,
+    Endfunc,
+    Stack,
+    Alias,
+    Unalias,
+}
+impl Directive {
+    /// The directive with this name (without the leading `.`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        DIRECTIVES
+            .iter()
+            .find(|(other, _)| *other == name)
+            .map(|(_, directive)| *directive)
+    }
+}
+
+/// Every directive by its name without the leading `.` (the lexer, suggestions and language server use this)
+pub const DIRECTIVES: &[(&str, Directive)] = &[
+    ("org", Directive::Org),
+    ("bank", Directive::Bank),
+    ("reachable", Directive::Reachable),
+    ("align", Directive::Align),
+    ("fill", Directive::Fill),
+    ("space", Directive::Space),
+    ("include_bytes", Directive::IncludeBytes),
+    ("arch", Directive::Arch),
+    ("rept", Directive::Rept),
+    ("endr", Directive::Endr),
+    ("func", Directive::Func),
+    ("endfunc", Directive::Endfunc),
+    ("stack", Directive::Stack),
+    ("alias", Directive::Alias),
+    ("unalias", Directive::Unalias),
+];
+
+fn unknown_directive(name: &str, location: Location) -> Located<LexError> {
+    let names = DIRECTIVES.iter().map(|(name, _)| *name);
+    let message = match suggest::closest(name, names) {
+        Some(suggestion) => format!(
+            "Unknown compiler directive. Did you mean '.{}'?",
+            suggestion
+        )
+        .into(),
+        None => Cow::Borrowed("Unknown compiler directive"),
+    };
+    LexError::located(message, location)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ValueToken<T> {
     LabelRef(Box<str>, RefType),
@@ -36,10 +102,7 @@ impl RefType {
         }
     }
     pub fn is_full(&self) -> bool {
-        match self {
-            Self::Full => true,
-            _ => false,
-        }
+        matches!(self, Self::Full)
     }
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -63,6 +126,8 @@ pub enum Token {
     Value(ValueToken<u8>),
     Reachable(ValueToken<u8>),
     Bank(u8),
+    Align(u16),
+    Fill { count: u16, value: u8 },
     Bytes(Vec<u8>),
     Op(OpToken),
     Arch(Architecture),
@@ -72,40 +137,108 @@ pub enum Token {
 #[derive(Debug, PartialEq, Eq)]
 pub struct LexError {
     message: Cow<'static, str>,
+    /// Secondary spans that give more context. (for example the operands an instruction expects)
+    notes: Vec<Located<String>>,
 }
 impl LexError {
     pub fn new(message: Cow<'static, str>) -> Self {
-        Self { message }
-    }
-    pub fn new_static(message: &'static str) -> Self {
         Self {
-            message: Cow::Borrowed(message),
+            message,
+            notes: Vec::new(),
         }
     }
+    pub fn new_static(message: &'static str) -> Self {
+        Self::new(Cow::Borrowed(message))
+    }
     pub fn located(message: Cow<'static, str>, location: Location) -> Located<Self> {
-        Located::new(Self { message }, location)
+        Located::new(Self::new(message), location)
     }
     pub fn static_located(message: &'static str, location: Location) -> Located<Self> {
-        Located::new(
-            Self {
-                message: Cow::Borrowed(message),
-            },
-            location,
-        )
+        Located::new(Self::new_static(message), location)
+    }
+}
+impl Located<LexError> {
+    pub fn with_note(mut self, message: String, location: Location) -> Self {
+        self.value.notes.push(Located::new(message, location));
+        self
     }
 }
 impl IntoAsmError for Located<LexError> {
     fn into_asm_error<'a>(self, code: &'a str, filename: std::rc::Rc<str>) -> super::AsmError<'a> {
-        super::AsmError {
+        let mut err = super::AsmError::new(
             filename,
             code,
-            location: self.location,
-            message: self.value.message.to_string(),
-        }
+            self.location,
+            self.value.message.to_string(),
+        );
+        err.notes = self.value.notes;
+        err
+    }
+}
+
+/// Something suspicious in the source that doesn't stop it from being assembled
+#[derive(Debug, PartialEq, Eq)]
+pub enum LexWarning {
+    /// An alias names the same register as another alias that is still in scope (the register and the other alias)
+    SharedRegister(&'static str, Located<Box<str>>),
+    /// A `.rept` counter has the name of a define or of the counter of an outer `.rept`
+    ShadowedSymbol(Box<str>),
+}
+impl IntoAsmError for Located<LexWarning> {
+    fn into_asm_error<'a>(self, code: &'a str, filename: std::rc::Rc<str>) -> super::AsmError<'a> {
+        let mut notes = Vec::new();
+        let message = match self.value {
+            LexWarning::SharedRegister(register, other) => {
+                notes.push(Located::new(
+                    format!("'{}' is also {}", other.value, register),
+                    other.location,
+                ));
+                format!("This alias names {} which already has an alias", register)
+            }
+            LexWarning::ShadowedSymbol(name) => {
+                format!(
+                    "The counter '{}' hides a define or outer counter with the same name",
+                    name
+                )
+            }
+        };
+        let mut warning = super::AsmError::warning(filename, code, self.location, message);
+        warning.notes = notes;
+        warning
+    }
+}
+
+/// A name for a register or calculation operation defined by `.alias`
+struct Alias {
+    name: Box<str>,
+    target: &'static str,
+    location: Location,
+    /// Aliases defined inside a `.func` are removed at its `.endfunc`
+    in_func: bool,
+}
+
+/// The alias a name refers to (later aliases shadow earlier ones with the same name)
+fn find_alias<'a>(aliases: &'a [Alias], name: &str) -> Option<&'a Alias> {
+    aliases.iter().rev().find(|alias| *alias.name == *name)
+}
+
+fn alias_targets() -> impl Iterator<Item = &'static str> + Clone {
+    Reg::VARIANTS.iter().chain(CalOp::VARIANTS).copied()
+}
+
+/// What a name is already used for if it can't be a symbol (like a `.rept` counter or a define).
+/// Symbols are looked up before instructions, so one named like an instruction would change how lines are parsed.
+pub fn reserved_name(name: &str) -> Option<&'static str> {
+    if Reg::VARIANTS.contains(&name) {
+        return Some("a register");
     }
+    let instruction = suggest::PSEUDO_OPS.contains(&name)
+        || Nna8v1::try_from_str(name).is_some()
+        || Nna8v2::try_from_str(name).is_some();
+    instruction.then_some("an instruction")
 }
 
-fn parse_identifier<'a>(str: &'a str) -> Option<&'a str> {
+fn parse_identifier(str: &str) -> Option<&str> {
     if str.starts_with(|c: char| c.is_ascii_digit()) {
         return None;
     }
@@ -117,27 +250,75 @@ fn parse_identifier<'a>(str: &'a str) -> Option<&'a str> {
     Some(str)
 }
 
-fn parse_value<T: ParseHex + ParseBin + MaxValue>(
+/// Parses the name of a label definition or reference.
+///
+/// Besides normal identifiers this accepts local labels (`.name`) and anonymous labels (`+` or `-`).
+/// References can also point to the local label of another global label (`global.name`).
+/// References to anonymous labels can repeat the sign to skip labels (`++` is the second next `+` label).
+fn parse_label_name(str: &str, reference: bool) -> Option<&str> {
+    if let Some(local) = str.strip_prefix('.') {
+        return parse_identifier(local).map(|_| str);
+    }
+    if let Some((global, local)) = str.split_once('.').filter(|_| reference) {
+        return parse_identifier(global)
+            .and(parse_identifier(local))
+            .map(|_| str);
+    }
+    for sign in ['+', '-'] {
+        if str.starts_with(sign) {
+            let valid = if reference {
+                str.chars().all(|c| c == sign)
+            } else {
+                str.len() == 1
+            };
+            return valid.then_some(str);
+        }
+    }
+    parse_identifier(str)
+}
+
+fn parse_value<T: ParseHex + ParseBin + MaxValue + TryFrom<u64>>(
     token: &str,
     location: Location,
+    symbols: &Symbols,
 ) -> std::result::Result<Option<Located<ValueToken<T>>>, Located<LexError>> {
-    if token.starts_with("0x") {
-        let value = T::parse_hex(&token[2..]).ok_or(LexError::located(
+    if let Some(hex) = token.strip_prefix("0x") {
+        let value = T::parse_hex(hex).ok_or(LexError::located(
             format!("Invalid {} bit hex literal", T::BIT_COUNT).into(),
             location.clone(),
         ))?;
         return Ok(Some(Located::new(ValueToken::Const(value), location)));
     }
-    if token.starts_with("0b") {
-        let value = T::parse_bin(&token[2..]).ok_or(LexError::located(
+    if let Some(bin) = token.strip_prefix("0b") {
+        let value = T::parse_bin(bin).ok_or(LexError::located(
             format!("Invalid {} bit binary literal", T::BIT_COUNT).into(),
             location.clone(),
         ))?;
         return Ok(Some(Located::new(ValueToken::Const(value), location)));
     }
 
-    if token.starts_with("&") {
-        let token = &token[1..];
+    if token.starts_with('(') {
+        let value = expr::eval(token, symbols)
+            .map_err(|message| LexError::located(message, location.clone()))?;
+        let value = T::try_from(value).map_err(|_| {
+            LexError::located(
+                format!("Expression result doesn't fit in {} bits", T::BIT_COUNT).into(),
+                location.clone(),
+            )
+        })?;
+        return Ok(Some(Located::new(ValueToken::Const(value), location)));
+    }
+    if let Some(value) = symbols.get(token) {
+        let value = T::try_from(*value).map_err(|_| {
+            LexError::located(
+                format!("Value of '{}' doesn't fit in {} bits", token, T::BIT_COUNT).into(),
+                location.clone(),
+            )
+        })?;
+        return Ok(Some(Located::new(ValueToken::Const(value), location)));
+    }
+
+    if let Some(token) = token.strip_prefix('&') {
         let (token, ref_type) = if let Some(token) = token.strip_suffix(".low") {
             (token, RefType::Low)
         } else if let Some(token) = token.strip_suffix(".high") {
@@ -145,7 +326,7 @@ fn parse_value<T: ParseHex + ParseBin + MaxValue>(
         } else {
             (token, RefType::Full)
         };
-        let value = parse_identifier(&token).ok_or(LexError::static_located(
+        let value = parse_label_name(token, true).ok_or(LexError::static_located(
             "Label ref contains invalid characters.",
             location.clone(),
         ))?;
@@ -176,14 +357,15 @@ fn parse_next_hex8(parser: &mut Parser) -> Result<u8> {
     Ok(Located::new(value, parser.location()))
 }
 
-fn parse_next_value<T: ParseBin + ParseHex + MaxValue>(
+fn parse_next_value<T: ParseBin + ParseHex + MaxValue + TryFrom<u64>>(
     parser: &mut Parser,
+    symbols: &Symbols,
 ) -> Result<ValueToken<T>> {
     let token = parser.next_same_line_or_err(Cow::Owned(format!(
         "Expected an {} bit value after this.",
         T::MAX_VALUE
     )))?;
-    match parse_value::<T>(token, parser.location())? {
+    match parse_value::<T>(token, parser.location(), symbols)? {
         Some(v) => Ok(v),
         None => Err(LexError::located(
             format!("Expected an {} bit value.", T::BIT_COUNT).into(),
@@ -191,7 +373,47 @@ fn parse_next_value<T: ParseBin + ParseHex + MaxValue>(
         )),
     }
 }
-fn parse_next_constarg(parser: &mut Parser, constarg: ConstArg) -> Result<u8> {
+/// Like [parse_next_value] but for an argument in a comma separated list. (a trailing ',' is skipped)
+fn parse_next_list_value<T: ParseBin + ParseHex + MaxValue + TryFrom<u64>>(
+    parser: &mut Parser,
+    symbols: &Symbols,
+) -> Result<ValueToken<T>> {
+    let mut token = parser.next_same_line_or_err(Cow::Owned(format!(
+        "Expected an {} bit value after this.",
+        T::BIT_COUNT
+    )))?;
+    if token == "," {
+        token = parser.next_same_line_or_err(Cow::Owned(format!(
+            "Expected an {} bit value after this.",
+            T::BIT_COUNT
+        )))?;
+    }
+    let token = token.strip_suffix(',').unwrap_or(token);
+    match parse_value::<T>(token, parser.location(), symbols)? {
+        Some(v) => Ok(v),
+        None => Err(LexError::located(
+            format!("Expected an {} bit value.", T::BIT_COUNT).into(),
+            parser.location(),
+        )),
+    }
+}
+
+/// Parses a value that has to be known while lexing. (so no label references)
+fn parse_next_const<T: ParseBin + ParseHex + MaxValue + TryFrom<u64>>(
+    parser: &mut Parser,
+    symbols: &Symbols,
+) -> Result<T> {
+    let value = parse_next_list_value::<T>(parser, symbols)?;
+    match value.value {
+        ValueToken::Const(v) => Ok(Located::new(v, value.location)),
+        ValueToken::LabelRef(_, _) => Err(LexError::static_located(
+            "Label references are not allowed here.",
+            value.location,
+        )),
+    }
+}
+
+fn parse_next_constarg(parser: &mut Parser, constarg: ConstArg, aliases: &[Alias]) -> Result<u8> {
     let token = parser.next_same_line_or_err(
         format!(
             "Expected a '{}' after this. Found end of file",
@@ -199,18 +421,44 @@ fn parse_next_constarg(parser: &mut Parser, constarg: ConstArg) -> Result<u8> {
         )
         .into(),
     )?;
+    let alias = find_alias(aliases, token);
+    let value = alias.map_or(token, |alias| alias.target);
     for (i, var) in constarg.variants.iter().enumerate() {
         if *var == "?" {
             continue;
         }
-        if *var == token {
+        if *var == value {
             return Ok(Located::new(i as u8, parser.location()));
         }
     }
-    Err(LexError::located(
-        format!("Invalid '{}'", constarg.name).into(),
-        parser.location(),
-    ))
+    if let Some(alias) = alias {
+        return Err(LexError::located(
+            format!(
+                "'{}' is an alias of '{}' which isn't a valid '{}'",
+                alias.name, alias.target, constarg.name
+            )
+            .into(),
+            parser.location(),
+        )
+        .with_note("alias defined here".to_string(), alias.location.clone()));
+    }
+    let valid = constarg.variants.iter().copied().filter(|var| *var != "?");
+    let fitting_aliases = aliases
+        .iter()
+        .filter(|alias| constarg.variants.contains(&alias.target))
+        .map(|alias| &*alias.name);
+    let message = match suggest::closest(token, valid.clone().chain(fitting_aliases)) {
+        Some(suggestion) => format!(
+            "Invalid '{}'. Did you mean '{}'?",
+            constarg.name, suggestion
+        ),
+        None => format!(
+            "Invalid '{}'. Expected one of: {}",
+            constarg.name,
+            valid.collect::<Vec<_>>().join(", ")
+        ),
+    };
+    Err(LexError::located(message.into(), parser.location()))
 }
 
 fn parse_next_str<'a>(parser: &'a mut Parser) -> Result<&'a str> {
@@ -233,71 +481,116 @@ fn parse_next_str<'a>(parser: &'a mut Parser) -> Result<&'a str> {
     Ok(Located::new(&token[1..token.len() - 1], parser.location()))
 }
 
-fn parse_compiler_directive<'a>(token: &'a str, parser: &mut Parser) -> Result<Token> {
+/// Parses the directives that become a single token
+fn parse_compiler_directive(
+    directive: Directive,
+    parser: &mut Parser,
+    symbols: &Symbols,
+) -> Result<Token> {
     let token_loc = parser.location();
-    match token {
-        "org" => {
+    match directive {
+        Directive::Org => {
             let addr = parse_next_hex8(parser)?;
-            return Ok(Located::new(
-                Token::Org(addr.value).into(),
+            Ok(Located::new(
+                Token::Org(addr.value),
                 token_loc.combine(addr.location),
-            ));
+            ))
         }
-        "bank" => {
+        Directive::Bank => {
             let addr = parse_next_hex8(parser)?;
-            return Ok(Located::new(
-                Token::Bank(addr.value).into(),
+            Ok(Located::new(
+                Token::Bank(addr.value),
                 token_loc.combine(addr.location),
-            ));
+            ))
         }
-        "reachable" => {
-            let start = parse_next_value::<u8>(parser)?;
+        Directive::Reachable => {
+            let start = parse_next_value::<u8>(parser, symbols)?;
 
-            return Ok(Located::new(
-                Token::Reachable(start.value).into(),
+            Ok(Located::new(
+                Token::Reachable(start.value),
                 token_loc.combine(start.location),
-            ));
+            ))
         }
-        "include_bytes" => {
+        Directive::Align => {
+            let alignment = parse_next_const::<u16>(parser, symbols)?;
+            if !alignment.value.is_power_of_two() {
+                return Err(LexError::static_located(
+                    "Alignment has to be a power of 2.",
+                    alignment.location,
+                ));
+            }
+            Ok(Located::new(
+                Token::Align(alignment.value),
+                token_loc.combine(alignment.location),
+            ))
+        }
+        Directive::Fill => {
+            let count = parse_next_const::<u16>(parser, symbols)?;
+            let value = parse_next_const::<u8>(parser, symbols)?;
+            Ok(Located::new(
+                Token::Fill {
+                    count: count.value,
+                    value: value.value,
+                },
+                token_loc.combine(value.location),
+            ))
+        }
+        Directive::Space => {
+            let count = parse_next_const::<u16>(parser, symbols)?;
+            Ok(Located::new(
+                Token::Fill {
+                    count: count.value,
+                    value: 0,
+                },
+                token_loc.combine(count.location),
+            ))
+        }
+        Directive::IncludeBytes => {
             let path_str = parse_next_str(parser)?;
             let path = PathBuf::from_str(&path_str).unwrap();
-            return Ok(Located::new(
+            Ok(Located::new(
                 Token::IncludeBytes(path),
                 token_loc.combine(parser.location()),
-            ));
+            ))
         }
-        "arch" => {
+        Directive::Arch => {
             let arch_t = parse_next_str(parser)?;
-            let arch = Architecture::from_str(arch_t.value).ok_or(LexError::static_located(
-                "Unknown architecture name",
-                arch_t.location.clone(),
-            ))?;
-            return Ok(Located::new(
+            let arch = Architecture::from_str(arch_t.value).map_err(|()| {
+                LexError::static_located("Unknown architecture name", arch_t.location.clone())
+            })?;
+            Ok(Located::new(
                 Token::Arch(arch),
                 token_loc.combine(arch_t.location),
-            ));
-        }
-        _ => {
-            return Err(LexError::static_located(
-                "Unknown compiler directive",
-                parser.location(),
-            ));
+            ))
         }
+        Directive::Rept
+        | Directive::Endr
+        | Directive::Func
+        | Directive::Endfunc
+        | Directive::Stack
+        | Directive::Alias
+        | Directive::Unalias => unreachable!("{:?} is handled by parse_lex", directive),
     }
 }
 
-fn parse_oparg(arg: OpArg, big: bool, parser: &mut Parser) -> Result<OpToken> {
+fn parse_oparg(
+    arg: OpArg,
+    big: bool,
+    parser: &mut Parser,
+    symbols: &Symbols,
+    aliases: &[Alias],
+) -> Result<OpToken> {
     match arg.ty {
         OpArgType::None => Ok(Located::new(OpToken::Full(0), parser.location())),
         OpArgType::Const(c) => {
-            let arg = parse_next_constarg(parser, c)?;
-            Ok(arg.map(|v| OpToken::Full(v)))
+            let arg = parse_next_constarg(parser, c, aliases)?;
+            Ok(arg.map(OpToken::Full))
         }
         OpArgType::Value { nz: true } => {
             if big {
                 panic!("Generating code for 4 bit nonzero values is currently not supported. But an instruction in the current arch exists that requires it.");
             }
-            Ok(match parse_next_value::<u4>(parser)? {
+            Ok(match parse_next_value::<u4>(parser, symbols)? {
                 Located {
                     location,
                     value: ValueToken::Const(v),
@@ -322,7 +615,7 @@ fn parse_oparg(arg: OpArg, big: bool, parser: &mut Parser) -> Result<OpToken> {
             })
         }
         OpArgType::Value { nz: false } => Ok(if big {
-            let value = parse_next_value::<u4>(parser)?;
+            let value = parse_next_value::<u4>(parser, symbols)?;
             let token = match value.value {
                 ValueToken4::Const(value) => OpToken::Full(value.into_low()),
                 ValueToken4::LabelRef(name, ref_type) => {
@@ -334,7 +627,7 @@ fn parse_oparg(arg: OpArg, big: bool, parser: &mut Parser) -> Result<OpToken> {
             };
             Located::new(token, parser.location().combine(value.location))
         } else {
-            match parse_next_value::<u2>(parser)? {
+            match parse_next_value::<u2>(parser, symbols)? {
                 Located {
                     location,
                     value: ValueToken::Const(v),
@@ -349,23 +642,46 @@ fn parse_oparg(arg: OpArg, big: bool, parser: &mut Parser) -> Result<OpToken> {
                     ))
                 }
             }
-            .map(|v| OpToken::Full(v))
+            .map(OpToken::Full)
         }),
     }
 }
 
-fn parse_op<I: Arch + Into<u8>>(token: &str, parser: &mut Parser) -> Result<OpToken> {
-    let op = I::try_from_str(token).ok_or(LexError::static_located(
-        "Unknown operation. See spec for available operations",
-        parser.location(),
-    ))?;
+fn parse_op<I: Arch + Into<u8>>(
+    token: &str,
+    parser: &mut Parser,
+    symbols: &Symbols,
+    aliases: &[Alias],
+) -> Result<OpToken> {
+    let Some(op) = I::try_from_str(token) else {
+        let mnemonics = suggest::mnemonics::<I>();
+        let candidates = mnemonics.iter().chain(suggest::PSEUDO_OPS).copied();
+        let message = match suggest::closest(token, candidates) {
+            Some(suggestion) => format!("Unknown operation. Did you mean '{}'?", suggestion).into(),
+            None => Cow::Borrowed("Unknown operation. See spec for available operations"),
+        };
+        return Err(LexError::located(message, parser.location()));
+    };
+    let location = parser.location();
+    parse_op_args(op, parser, symbols, aliases).map_err(|err| {
+        let signature = format!("{} {}", op.name(), op.args());
+        err.with_note(format!("expected `{}`", signature.trim_end()), location)
+    })
+}
+
+fn parse_op_args<I: Arch + Into<u8>>(
+    op: I,
+    parser: &mut Parser,
+    symbols: &Symbols,
+    aliases: &[Alias],
+) -> Result<OpToken> {
     Ok(match op.args() {
-        OpArgs::Arg(a) => parse_oparg(a.0, true, parser)?.map(|t| match t {
+        OpArgs::Arg(a) => parse_oparg(a.0, true, parser, symbols, aliases)?.map(|t| match t {
             OpToken::Full(v) => OpToken::Full(op.into() | v),
             OpToken::LabelRef(v, name, ty) => OpToken::LabelRef(op.into() | v, name, ty),
         }),
         OpArgs::ArgArg(a, b) => {
-            let a = parse_oparg(a, false, parser)?
+            let a = parse_oparg(a, false, parser, symbols, aliases)?
                 .map(|a| {
                     a.full_or(LexError::static_located(
                         "Cant use a reference for a 2 bit value",
@@ -373,7 +689,7 @@ fn parse_op<I: Arch + Into<u8>>(token: &str, parser: &mut Parser) -> Result<OpTo
                     ))
                 })
                 .lift_ok()?;
-            let b = parse_oparg(b, false, parser)?
+            let b = parse_oparg(b, false, parser, symbols, aliases)?
                 .map(|b| {
                     b.full_or(LexError::static_located(
                         "Cant use a refference for a 2 bit value",
@@ -390,9 +706,99 @@ fn parse_op<I: Arch + Into<u8>>(token: &str, parser: &mut Parser) -> Result<OpTo
     })
 }
 
+/// A `.rept` block that is currently being repeated
+struct Rept<'a> {
+    /// The parser positioned right after the `.rept` directive
+    start: Parser<'a>,
+    location: Location,
+    count: u64,
+    index: u64,
+    counter: Option<Box<str>>,
+    /// The value the counter symbol had before this block shadowed it
+    shadowed: Option<u64>,
+}
+
+/// Parses the arguments of a `.rept count [counter]` directive
+fn parse_rept<'a>(
+    parser: &mut Parser<'a>,
+    symbols: &mut Symbols,
+    warnings: &mut Vec<Located<LexWarning>>,
+) -> Result<Rept<'a>> {
+    let location = parser.location();
+    let count = parse_next_value::<u16>(parser, symbols)?;
+    let ValueToken::Const(count_value) = count.value else {
+        return Err(LexError::static_located(
+            "The repeat count can't be a label reference.",
+            count.location,
+        ));
+    };
+    let mut location = location.combine(count.location);
+
+    let counter = match parser.next_same_line() {
+        Some(token) => {
+            let name = parse_identifier(token).ok_or(LexError::static_located(
+                "invalid counter name",
+                parser.location(),
+            ))?;
+            if let Some(kind) = reserved_name(name) {
+                return Err(LexError::located(
+                    format!("'{}' is {} and can't be used as a counter name", name, kind).into(),
+                    parser.location(),
+                ));
+            }
+            location = location.combine(parser.location());
+            Some(Box::<str>::from(name))
+        }
+        None => None,
+    };
+    let shadowed = counter
+        .as_ref()
+        .and_then(|name| symbols.insert(name.clone(), 0));
+    if let (Some(name), Some(_)) = (&counter, shadowed) {
+        let warning = Located::new(LexWarning::ShadowedSymbol(name.clone()), location.clone());
+        // nested blocks are parsed again for every iteration of the outer one
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    Ok(Located::new(
+        Rept {
+            start: parser.clone(),
+            location: location.clone(),
+            count: count_value as u64,
+            index: 0,
+            counter,
+            shadowed,
+        },
+        location,
+    ))
+}
+
+/// Skips to the `.endr` matching the `.rept` that was just parsed
+fn skip_rept_body(parser: &mut Parser, rept_location: Location) -> Result<()> {
+    let mut depth = 0;
+    loop {
+        let Some(token) = parser.next() else {
+            return Err(LexError::static_located(
+                "This .rept is missing an .endr",
+                rept_location,
+            ));
+        };
+        match token.strip_prefix('.').and_then(Directive::from_name) {
+            Some(Directive::Rept) => depth += 1,
+            Some(Directive::Endr) if depth == 0 => return Ok(Located::new((), parser.location())),
+            Some(Directive::Endr) => depth -= 1,
+            _ => {}
+        }
+    }
+}
+
 pub fn parse_lex(
     input: &str,
     default_arch: Architecture,
+    defines: &Symbols,
+    warnings: &mut Vec<Located<LexWarning>>,
 ) -> std::result::Result<Vec<Located<Token>>, Located<LexError>> {
     let mut out_vec = Vec::new();
     let Some(mut parser) = Parser::new(input) else {
@@ -401,32 +807,83 @@ pub fn parse_lex(
 
     let mut arch = default_arch;
     let mut parsed_ops = false;
+    let mut symbols = defines.clone();
+    let mut repts: Vec<Rept> = Vec::new();
+    let mut bank = 0;
+    let mut func: Option<Func> = None;
+    let mut stack: Option<Stack> = None;
+    let mut call_count = 0;
+    let mut aliases: Vec<Alias> = Vec::new();
+    // the register values known at the end of `out_vec[..tracked]` (used by li)
+    let mut regs = Regs::default();
+    let mut tracked = 0;
 
     loop {
         let Some(token) = parser.next() else {
+            if let Some(rept) = repts.pop() {
+                return Err(LexError::static_located(
+                    "This .rept is missing an .endr",
+                    rept.location,
+                ));
+            }
+            if let Some(func) = func {
+                return Err(LexError::static_located(
+                    "This .func is missing an .endfunc",
+                    func.location,
+                ));
+            }
             return Ok(out_vec);
         };
         //println!("token: '{}'", token);
-        if token.starts_with('.') {
-            let t = parse_compiler_directive(&token[1..], &mut parser)?;
-            match t.value {
-                Token::Arch(a) => {
-                    if parsed_ops {
-                        return Err(LexError::static_located(
-                            "Can't use .arch after some operations have been parsed.",
-                            parser.location(),
-                        ));
-                    }
-                    arch = a
-                }
-                _ => {}
-            };
-            out_vec.push(t);
-            continue;
+        match token {
+            "ret" => {
+                let Some(func) = &func else {
+                    return Err(pseudo::no_func_error("ret", parser.location()));
+                };
+                parsed_ops = true;
+                out_vec.extend(func.ret(arch, parser.location()));
+                continue;
+            }
+            "call" => {
+                let location = parser.location();
+                let target = parser
+                    .next_same_line_or_err(Cow::Borrowed("Expected a label to call after this."))?;
+                let target = target.strip_prefix('&').unwrap_or(target);
+                let target = parse_label_name(target, true).ok_or(LexError::static_located(
+                    "Label ref contains invalid characters.",
+                    parser.location(),
+                ))?;
+                parsed_ops = true;
+                out_vec.extend(pseudo::call(
+                    arch,
+                    target,
+                    call_count,
+                    location.combine(parser.location()),
+                ));
+                call_count += 1;
+                continue;
+            }
+            "li" => {
+                let location = parser.location();
+                let reg = parse_next_constarg(&mut parser, ConstArg::new::<Reg>(), &aliases)?;
+                let value = parse_next_value::<u8>(&mut parser, &symbols)?;
+                pseudo::track_regs(&mut regs, arch, &out_vec[tracked..]);
+                tracked = out_vec.len();
+                parsed_ops = true;
+                out_vec.extend(pseudo::li(
+                    arch,
+                    reg.value,
+                    value.value,
+                    &regs,
+                    location.combine(value.location),
+                ));
+                continue;
+            }
+            _ => {}
         }
-        if token.ends_with(':') {
+        if let Some(label) = token.strip_suffix(':') {
             out_vec.push(
-                parse_identifier(&token[..token.len() - 1])
+                parse_label_name(label, false)
                     .map(|label| Located::new(Token::LabelDef(label.into()), parser.location()))
                     .ok_or(LexError::static_located(
                         "invalid label name",
@@ -435,26 +892,328 @@ pub fn parse_lex(
             );
             continue;
         }
+        if let Some(name) = token.strip_prefix('.') {
+            let directive = Directive::from_name(name)
+                .ok_or_else(|| unknown_directive(name, parser.location()))?;
+            match directive {
+                Directive::Rept => {
+                    let rept = parse_rept(&mut parser, &mut symbols, warnings)?;
+                    if rept.value.count == 0 {
+                        skip_rept_body(&mut parser, rept.location)?;
+                        if let Some(counter) = rept.value.counter {
+                            restore_symbol(&mut symbols, counter, rept.value.shadowed);
+                        }
+                    } else {
+                        repts.push(rept.value);
+                    }
+                }
+                Directive::Endr => {
+                    let Some(mut rept) = repts.pop() else {
+                        return Err(LexError::static_located(
+                            ".endr without a matching .rept",
+                            parser.location(),
+                        ));
+                    };
+                    rept.index += 1;
+                    if rept.index < rept.count {
+                        parser = rept.start.clone();
+                        if let Some(counter) = &rept.counter {
+                            symbols.insert(counter.clone(), rept.index);
+                        }
+                        repts.push(rept);
+                    } else if let Some(counter) = rept.counter {
+                        restore_symbol(&mut symbols, counter, rept.shadowed);
+                    }
+                }
+                Directive::Func => {
+                    let new_func = parse_func(&mut parser, arch, bank, stack.as_ref())?;
+                    if func.is_some() {
+                        return Err(LexError::static_located(
+                            "Functions can't be nested. (missing .endfunc?)",
+                            new_func.location,
+                        ));
+                    }
+                    parsed_ops = true;
+                    out_vec.extend(new_func.prologue(arch));
+                    func = Some(new_func);
+                }
+                Directive::Stack => {
+                    let mut location = parser.location();
+                    let Some((pointer, bank)) =
+                        parse_slot(&mut parser, bank, "stack pointer", &mut location)?
+                    else {
+                        return Err(LexError::static_located(
+                            "Expected a label reference to the stack pointer after this.",
+                            location,
+                        ));
+                    };
+                    // the value the pointer starts with (0x00 for the top of the bank)
+                    let top = match parser.next_same_line() {
+                        Some(token) => {
+                            let top = token.strip_prefix("0x").and_then(u8::parse_hex).ok_or(
+                                LexError::static_located(
+                                    "Expected the 8 bit address of the top of the stack.",
+                                    parser.location(),
+                                ),
+                            )?;
+                            location = location.combine(parser.location());
+                            top
+                        }
+                        None => 0x00,
+                    };
+                    // the first return address is pushed below the top
+                    check_writable(arch, bank, Some(top.wrapping_sub(1)), "stack", location)?;
+                    stack = Some(Stack { pointer, bank });
+                }
+                Directive::Endfunc => {
+                    let Some(ended) = func.take() else {
+                        return Err(pseudo::no_func_error(".endfunc", parser.location()));
+                    };
+                    out_vec.extend(ended.end(arch, parser.location()));
+                    aliases.retain(|alias| !alias.in_func);
+                }
+                Directive::Alias => {
+                    parse_alias(&mut parser, &mut aliases, func.is_some(), warnings)?;
+                }
+                Directive::Unalias => {
+                    let name = parser.next_same_line_or_err(Cow::Borrowed(
+                        "Expected an alias name after this.",
+                    ))?;
+                    let Some(i) = aliases.iter().rposition(|alias| *alias.name == *name) else {
+                        return Err(LexError::static_located(
+                            "No alias with this name is defined",
+                            parser.location(),
+                        ));
+                    };
+                    aliases.remove(i);
+                }
+                _ => {
+                    let t = parse_compiler_directive(directive, &mut parser, &symbols)?;
+                    match t.value {
+                        Token::Arch(a) => {
+                            if parsed_ops {
+                                return Err(LexError::static_located(
+                                    "Can't use .arch after some operations have been parsed.",
+                                    parser.location(),
+                                ));
+                            }
+                            arch = a
+                        }
+                        Token::Bank(b) => bank = b,
+                        _ => {}
+                    };
+                    out_vec.push(t);
+                }
+            }
+            continue;
+        }
 
-        if let Some(value) = parse_value::<u8>(token, parser.location())? {
-            out_vec.push(value.map(|vt| Token::Value(vt)));
+        if let Some(value) = parse_value::<u8>(token, parser.location(), &symbols)? {
+            out_vec.push(value.map(Token::Value));
             continue;
         }
 
         parsed_ops = true;
         out_vec.push(
             match arch {
-                Architecture::Nna8v1 => parse_op::<Nna8v1>(token, &mut parser),
-                Architecture::Nna8v2 => parse_op::<Nna8v2>(token, &mut parser),
+                Architecture::Nna8v1 => parse_op::<Nna8v1>(token, &mut parser, &symbols, &aliases),
+                Architecture::Nna8v2 => parse_op::<Nna8v2>(token, &mut parser, &symbols, &aliases),
             }?
-            .map(|opt| Token::Op(opt)),
+            .map(Token::Op),
         );
     }
 }
 
+/// Parses an optional `&label [bank]` (the bank defaults to the current one)
+fn parse_slot(
+    parser: &mut Parser,
+    bank: u8,
+    what: &str,
+    location: &mut Location,
+) -> std::result::Result<Option<(Box<str>, u8)>, Located<LexError>> {
+    let Some(token) = parser.next_same_line() else {
+        return Ok(None);
+    };
+    let label = token
+        .strip_prefix('&')
+        .and_then(|label| parse_label_name(label, true))
+        .ok_or(LexError::located(
+            format!("Expected a label reference to the {}.", what).into(),
+            parser.location(),
+        ))?;
+    *location = location.clone().combine(parser.location());
+
+    let bank = match parser.next_same_line() {
+        Some(token) => {
+            let value =
+                token
+                    .strip_prefix("0x")
+                    .and_then(u8::parse_hex)
+                    .ok_or(LexError::located(
+                        format!("Expected the 8 bit bank of the {}.", what).into(),
+                        parser.location(),
+                    ))?;
+            *location = location.clone().combine(parser.location());
+            value
+        }
+        None => bank,
+    };
+    Ok(Some((label.into(), bank)))
+}
+
+/// Errors if the program can't write to memory in a bank (like flash on nna8v2)
+/// or at the address if it is known. (the end of a bank can be mapped to peripherals)
+fn check_writable(
+    arch: Architecture,
+    bank: u8,
+    addr: Option<u8>,
+    what: &str,
+    location: Location,
+) -> std::result::Result<(), Located<LexError>> {
+    if !arch.is_writable(bank) {
+        return Err(LexError::located(
+            format!(
+                "Bank {:#04x} can't be written so it can't hold the {}. Use a bank in RAM",
+                bank, what
+            )
+            .into(),
+            location,
+        ));
+    }
+    let memory = arch.memory_range(bank);
+    match addr {
+        Some(addr) if !memory.contains(&(addr as usize)) => Err(LexError::located(
+            format!(
+                "The {} starts at {:#04x} but only {:#04x}..{:#04x} of bank {:#04x} is memory",
+                what, addr, memory.start, memory.end, bank
+            )
+            .into(),
+            location,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Parses the arguments of a `.func name [&slot [bank]]` directive.
+/// Without a slot the return address is pushed onto the `.stack` (or kept in a byte emitted at `.endfunc`).
+fn parse_func(
+    parser: &mut Parser,
+    arch: Architecture,
+    bank: u8,
+    stack: Option<&Stack>,
+) -> std::result::Result<Func, Located<LexError>> {
+    let mut location = parser.location();
+    let name =
+        parser.next_same_line_or_err(Cow::Borrowed("Expected a function name after this."))?;
+    let name = parse_identifier(name).ok_or(LexError::static_located(
+        "invalid function name",
+        parser.location(),
+    ))?;
+    location = location.combine(parser.location());
+
+    let slot = parse_slot(parser, bank, "return address slot", &mut location)?;
+    Ok(match (slot, stack) {
+        (Some((slot, bank)), _) => {
+            check_writable(arch, bank, None, "return address slot", location.clone())?;
+            Func::new(name.into(), Some(slot), bank, location)
+        }
+        (None, Some(stack)) => Func::with_stack(name.into(), stack.clone(), location),
+        (None, None) => {
+            if !arch.is_writable(bank) {
+                return Err(LexError::located(
+                    format!(
+                        "Bank {:#04x} can't be written so this function needs a return address slot in RAM (.func {} &slot 0x80) or a .stack",
+                        bank, name
+                    )
+                    .into(),
+                    location,
+                ));
+            }
+            Func::new(name.into(), None, bank, location)
+        }
+    })
+}
+
+/// Parses a `.alias name target` directive and defines the alias.
+/// The target is a register, calculation operation or another alias.
+fn parse_alias(
+    parser: &mut Parser,
+    aliases: &mut Vec<Alias>,
+    in_func: bool,
+    warnings: &mut Vec<Located<LexWarning>>,
+) -> std::result::Result<(), Located<LexError>> {
+    let mut location = parser.location();
+    let name = parser.next_same_line_or_err(Cow::Borrowed("Expected an alias name after this."))?;
+    let name = parse_identifier(name).ok_or(LexError::static_located(
+        "invalid alias name",
+        parser.location(),
+    ))?;
+    if alias_targets().any(|target| target == name) {
+        return Err(LexError::static_located(
+            "An alias can't be named like a register or calculation operation",
+            parser.location(),
+        ));
+    }
+    let token = parser.next_same_line_or_err(Cow::Borrowed(
+        "Expected a register or calculation operation after this.",
+    ))?;
+    location = location.combine(parser.location());
+    let target = match find_alias(aliases, token) {
+        Some(alias) => alias.target,
+        None => alias_targets()
+            .find(|target| *target == token)
+            .ok_or_else(|| {
+                let message = match suggest::closest(token, alias_targets()) {
+                    Some(suggestion) => format!(
+                        "Expected a register or calculation operation. Did you mean '{}'?",
+                        suggestion
+                    )
+                    .into(),
+                    None => Cow::Borrowed("Expected a register (r0-r3) or calculation operation"),
+                };
+                LexError::located(message, parser.location())
+            })?,
+    };
+
+    // redefining an alias in the same scope replaces it
+    aliases.retain(|alias| !(*alias.name == *name && alias.in_func == in_func));
+    if Reg::VARIANTS.contains(&target) {
+        let mut seen: Vec<&str> = vec![name];
+        for alias in aliases.iter().rev() {
+            if seen.contains(&&*alias.name) {
+                continue;
+            }
+            seen.push(&alias.name);
+            if alias.target == target {
+                warnings.push(Located::new(
+                    LexWarning::SharedRegister(
+                        target,
+                        Located::new(alias.name.clone(), alias.location.clone()),
+                    ),
+                    location.clone(),
+                ));
+            }
+        }
+    }
+    aliases.push(Alias {
+        name: name.into(),
+        target,
+        location,
+        in_func,
+    });
+    Ok(())
+}
+
+fn restore_symbol(symbols: &mut Symbols, name: Box<str>, shadowed: Option<u64>) {
+    match shadowed {
+        Some(value) => symbols.insert(name, value),
+        None => symbols.remove(&name),
+    };
+}
+
 #[cfg(test)]
 mod test {
-    use crate::asm::{lex::Token, Located};
+    use crate::asm::{expr::Symbols, lex::Token, Located};
 
     use super::parse_lex;
 
@@ -465,7 +1224,12 @@ mod test {
 
 ;comment "#;
         assert_eq!(
-            parse_lex(code, libnna::Architecture::Nna8v1),
+            parse_lex(
+                code,
+                libnna::Architecture::Nna8v1,
+                &Symbols::new(),
+                &mut Vec::new()
+            ),
             Ok(vec![Located::new(Token::Org(0xAB), (1, 0..9).into())])
         )
     }
@@ -476,7 +1240,12 @@ mod test {
 .org 0xAB
         "#;
         assert_eq!(
-            parse_lex(code, libnna::Architecture::Nna8v1),
+            parse_lex(
+                code,
+                libnna::Architecture::Nna8v1,
+                &Symbols::new(),
+                &mut Vec::new()
+            ),
             Ok(vec![
                 Located::new(Token::Arch(libnna::Architecture::Nna8v2), (0, 0..14).into()),
                 Located::new(Token::Org(0xAB), (1, 0..9).into())
@@ -0,0 +1,213 @@
+//! Warnings about suspicious register usage found by following the control flow graph
+
+use std::rc::Rc;
+
+use libnna::Register;
+
+use super::{
+    cfg::{Cfg, Op},
+    codegen::Addr,
+    AsmError, IntoAsmError, Located, Location,
+};
+
+#[derive(Debug)]
+pub enum Lint {
+    /// A register is read but isn't written on any path from the start of the program
+    UnwrittenRead(u8),
+    /// `cal` is executed but `mco` isn't on any path from the start of the program
+    CalWithoutMco,
+    /// The value in r0 is overwritten by lil/lih before it is used. (contains where it was written)
+    R0Clobbered(&'static str, Location),
+}
+impl IntoAsmError for Located<Lint> {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a> {
+        let mut notes = Vec::new();
+        let message = match self.value {
+            Lint::UnwrittenRead(reg) => {
+                format!("r{} is read but never written before", reg)
+            }
+            Lint::CalWithoutMco => {
+                "cal is used but the calc operation is never set using mco before".to_string()
+            }
+            Lint::R0Clobbered(name, written) => {
+                notes.push(Located::new("value written here".to_string(), written));
+                format!("{} overwrites the value in r0 before it is used", name)
+            }
+        };
+        let mut warning = AsmError::warning(filename, code, self.location, message);
+        warning.notes = notes;
+        warning
+    }
+}
+
+/// Bit of the co register in a register set (r0-r3 use bits 0-3)
+const CO: u8 = 1 << 4;
+const ALL: u8 = 0x1F;
+
+/// The registers (r0-r3 and co) an instruction reads or writes
+fn registers(op: &Op, regs: &[Register]) -> u8 {
+    let mut set = 0;
+    for reg in regs {
+        match reg.gpr(op.byte) {
+            Some(gpr) => set |= 1 << gpr,
+            None if *reg == Register::Co => set |= CO,
+            None => {}
+        }
+    }
+    set
+}
+
+fn reads(op: &Op) -> u8 {
+    // xor rN rN is used to zero a register. The old value doesn't matter
+    if op.name == "xor" && Register::A.gpr(op.byte) == Register::B.gpr(op.byte) {
+        return 0;
+    }
+    registers(op, op.effects.reads)
+}
+
+pub fn lint(cfg: &Cfg) -> Vec<Located<Lint>> {
+    let mut lints = Vec::new();
+    unwritten_reads(cfg, &mut lints);
+    for block in cfg.blocks.iter() {
+        clobbered_r0(&block.ops, &mut lints);
+    }
+    lints
+}
+
+/// Finds reads of registers that may not have been written on any path from the start of the program.
+/// Blocks that are only reached by jumps with unknown targets are assumed to have everything written.
+fn unwritten_reads(cfg: &Cfg, lints: &mut Vec<Located<Lint>>) {
+    let entry = Addr { bank: 0, addr: 0 };
+    let mut preds = vec![Vec::new(); cfg.blocks.len()];
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        for target in block.edges.iter().filter_map(|edge| edge.target) {
+            preds[target].push(i);
+        }
+    }
+    let initial = |i: usize| match cfg.blocks[i].start() == entry {
+        true => Some(0),
+        false if preds[i].is_empty() => Some(ALL),
+        false => None,
+    };
+
+    // the registers that are written on at least one path to the end of a block
+    let mut written = vec![0u8; cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            let mut state = initial(i).unwrap_or(0);
+            for pred in preds[i].iter() {
+                state |= written[*pred];
+            }
+            for op in block.ops.iter() {
+                state |= registers(op, op.effects.writes);
+            }
+            if state != written[i] {
+                written[i] = state;
+                changed = true;
+            }
+        }
+    }
+
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let mut state = initial(i).unwrap_or(0);
+        for pred in preds[i].iter() {
+            state |= written[*pred];
+        }
+        for op in block.ops.iter() {
+            let unwritten = reads(op) & !state;
+            for reg in (0..4).filter(|reg| unwritten & 1 << reg != 0) {
+                lints.push(Located::new(Lint::UnwrittenRead(reg), op.location.clone()));
+            }
+            if unwritten & CO != 0 {
+                lints.push(Located::new(Lint::CalWithoutMco, op.location.clone()));
+            }
+            // only warn once per register
+            state |= reads(op) | registers(op, op.effects.writes);
+        }
+    }
+}
+
+/// Finds values in r0 that are overwritten by lil/lih before they are read. (only inside a block)
+fn clobbered_r0(ops: &[Op], lints: &mut Vec<Located<Lint>>) {
+    // unread values in r0: a full value written by any other instruction and the halves written by lil/lih
+    let mut full: Option<&Op> = None;
+    let mut low: Option<&Op> = None;
+    let mut high: Option<&Op> = None;
+    for op in ops {
+        if reads(op) & 1 != 0 {
+            (full, low, high) = (None, None, None);
+        }
+        let (half, other) = match op.name {
+            "lil" => (&mut low, &high),
+            "lih" => (&mut high, &low),
+            _ => {
+                if registers(op, op.effects.writes) & 1 != 0 {
+                    (full, low, high) = (Some(op), None, None);
+                }
+                continue;
+            }
+        };
+        let clobbered = match (*half, full) {
+            (Some(prev), _) => Some(prev),
+            // both halves of the full value are replaced now
+            (None, Some(prev)) if other.is_some() => {
+                full = None;
+                Some(prev)
+            }
+            _ => None,
+        };
+        if let Some(prev) = clobbered {
+            lints.push(Located::new(
+                Lint::R0Clobbered(op.name, prev.location.clone()),
+                op.location.clone(),
+            ));
+        }
+        *half = Some(op);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::{lint, Lint};
+    use crate::asm::{assemble, cfg::Cfg, Options};
+
+    fn lints(code: &str) -> Vec<(usize, Lint)> {
+        let program = assemble(code, &Options::new(Architecture::Nna8v2)).unwrap();
+        lint(&Cfg::new(&program))
+            .into_iter()
+            .map(|lint| {
+                let (line, _): (usize, _) = lint.location.into();
+                (line, lint.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unwritten_reads() {
+        let found = lints(".org 0x00\nmov r2 r1\nmov r3 r1\nxor r2 r2\ncal r2 r3\n");
+        assert!(matches!(
+            found[..],
+            [(1, Lint::UnwrittenRead(1)), (4, Lint::CalWithoutMco)]
+        ));
+
+        assert!(lints(".org 0x00\nxor r1 r1\nmco sub\ncal r1 r1\n").is_empty());
+    }
+
+    #[test]
+    fn clobbered_r0() {
+        let found = lints(".org 0x00\nxor r1 r1\nmrd r0 r1\nlil 0x1\nlih 0x2\nlil 0x3\n");
+        assert!(matches!(
+            found[..],
+            [
+                (4, Lint::R0Clobbered("lih", _)),
+                (5, Lint::R0Clobbered("lil", _))
+            ]
+        ));
+
+        assert!(lints(".org 0x00\nxor r0 r0\nlil 0x5\nmov r1 r0\n").is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+//! A listing of the assembled instructions next to the source lines they came from
+
+use std::{collections::HashMap, fmt::Write};
+
+use super::{
+    cfg::Cfg,
+    codegen::{Addr, Program, Symbol},
+    Location,
+};
+
+/// Lists every byte of the program by address: instructions with their disassembly and data as values.
+/// A source line is only shown on the first byte it produced
+/// so the instructions a pseudo instruction expands to are listed below it.
+pub fn listing(program: &Program, code: &str) -> String {
+    let cfg = Cfg::new(program);
+    let mut rows: Vec<(Addr, u8, String, &Location)> = cfg
+        .blocks
+        .iter()
+        .flat_map(|block| block.ops.iter())
+        .map(|op| (op.addr, op.byte, op.text(), op.location))
+        .collect();
+    rows.extend(program.data.iter().map(|data| {
+        let byte = program.banks[data.bank as usize][data.addr as usize];
+        (data.value, byte, format!("{:#04x}", byte), &data.location)
+    }));
+    rows.sort_by_key(|(addr, _, _, _)| *addr);
+
+    let mut labels: HashMap<Addr, Vec<&Symbol>> = HashMap::new();
+    for symbol in program.symbols.iter() {
+        labels.entry(symbol.addr).or_default().push(&symbol.value);
+    }
+
+    let lines: Vec<&str> = code.lines().collect();
+    let mut out = String::new();
+    let mut last: Option<usize> = None;
+    for (addr, byte, text, location) in rows {
+        for label in labels.get(&addr).into_iter().flatten() {
+            let _ = writeln!(out, "{:16}{}:", "", label.display_name());
+        }
+        let mut line = format!("{}  {:02x}  {:16}", addr, byte, text);
+        let (number, _): (usize, _) = location.clone().into();
+        if last != Some(number) {
+            let source = lines.get(number).copied().unwrap_or_default().trim();
+            let _ = write!(line, "{:>4} | {}", number + 1, source);
+        }
+        last = Some(number);
+        let _ = writeln!(out, "{}", line.trim_end());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::listing;
+    use crate::asm::{assemble, Options};
+
+    #[test]
+    fn li_expansion() {
+        let code = ".org 0x00\nstart:\nli r1 0x1a\nxor r0 r0\nli r2 0x05\nli r2 0x07\n";
+        let program = assemble(code, &Options::new(Architecture::Nna8v2)).unwrap();
+        assert_eq!(
+            listing(&program, code),
+            "                start:
+0x00:0x00  9a  lil 0xa            3 | li r1 0x1a
+0x00:0x01  a1  lih 0x1
+0x00:0x02  b4  mov r1 r0
+0x00:0x03  d0  xor r0 r0          4 | xor r0 r0
+0x00:0x04  95  lil 0x5            5 | li r2 0x05
+0x00:0x05  b8  mov r2 r0
+0x00:0x06  e9  inc r2 0x2         6 | li r2 0x07
+"
+        );
+    }
+
+    #[test]
+    fn data() {
+        let code = ".org 0x00\nnop\ntable:\n0x12 &table\n.fill 0x2 0xff\n";
+        let program = assemble(code, &Options::new(Architecture::Nna8v2)).unwrap();
+        assert_eq!(
+            listing(&program, code),
+            "0x00:0x00  00  nop                2 | nop
+                table:
+0x00:0x01  12  0x12               4 | 0x12 &table
+0x00:0x02  01  0x01
+0x00:0x03  ff  0xff               5 | .fill 0x2 0xff
+0x00:0x04  ff  0xff
+"
+        );
+    }
+}
@@ -1,22 +1,41 @@
 use std::{
-    fs::File,
-    io::Read,
+    io,
     ops::{Deref, Range},
+    path::{Path, PathBuf},
     rc::Rc,
 };
 
 use libnna::Architecture;
 
 use self::lex::parse_lex;
-pub use codegen::Bank;
+pub use codegen::{Bank, Program};
+use codegen::{CodeGenError, CodeGenWarning};
+pub use expr::Symbols;
+use files::{FileProvider, FsFiles};
+pub use lex::{reserved_name, Directive, LexError, LexWarning, DIRECTIVES};
+use lint::Lint;
+use peephole::Optimization;
+pub use suggest::instructions;
 
+pub mod cfg;
 pub mod codegen;
+mod expr;
+pub mod files;
+pub mod fmt;
 mod lex;
+pub mod lint;
+pub mod listing;
 mod parse;
+pub mod peephole;
+mod pseudo;
+mod suggest;
+pub mod timing;
 
-const COLOR_RED: &'static str = "\x1b[31m";
-const BOLD: &'static str = "\x1b[1m";
-const RESET: &'static str = "\x1b[0m";
+const COLOR_RED: &str = "\x1b[31m";
+const COLOR_YELLOW: &str = "\x1b[33m";
+const COLOR_BLUE: &str = "\x1b[34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Location(usize, Range<usize>);
@@ -28,9 +47,9 @@ impl From<(usize, Range<usize>)> for Location {
         Self(value.0, value.1)
     }
 }
-impl Into<(usize, Range<usize>)> for Location {
-    fn into(self) -> (usize, Range<usize>) {
-        (self.0, self.1)
+impl From<Location> for (usize, Range<usize>) {
+    fn from(val: Location) -> Self {
+        (val.0, val.1)
     }
 }
 impl Location {
@@ -88,16 +107,53 @@ impl<T, E> Located<Result<T, E>> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    /// Doesn't stop the program from being assembled
+    Warning,
+    /// Information about what the assembler did. (for example a change made by the optimizer)
+    Note,
+}
+
 #[derive(Debug)]
 pub struct AsmError<'a> {
+    pub severity: Severity,
     pub filename: Rc<str>,
     pub code: &'a str,
     pub location: Location,
     pub message: String,
+    /// Secondary spans that give more context. (for example where a label was first defined)
+    pub notes: Vec<Located<String>>,
 }
 impl<'a> AsmError<'a> {
     const VIEW_SIZE: usize = 2;
 
+    pub fn new(filename: Rc<str>, code: &'a str, location: Location, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            filename,
+            code,
+            location,
+            message,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(filename: Rc<str>, code: &'a str, location: Location, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(filename, code, location, message)
+        }
+    }
+
+    pub fn note(filename: Rc<str>, code: &'a str, location: Location, message: String) -> Self {
+        Self {
+            severity: Severity::Note,
+            ..Self::new(filename, code, location, message)
+        }
+    }
+
     fn write_gutter(out: &mut String, line_num: Option<usize>, max_len: usize) {
         match line_num {
             Some(lnum) => {
@@ -126,104 +182,295 @@ impl<'a> AsmError<'a> {
         digits
     }
 
-    pub fn print(&self) {
+    fn write_span(out: &mut String, span: Range<usize>, marker: char, color: &str, message: &str) {
+        for _ in 0..span.start {
+            out.push(' ');
+        }
+        out.push_str(color);
+        let count = (span.end - span.start).max(1);
+        for _ in 0..count {
+            out.push(marker);
+        }
+        out.push(' ');
+        out.push_str(message);
+        out.push_str(RESET);
+        out.push('\n');
+    }
+
+    /// Renders the error with the surrounding code of every span
+    pub fn render(&self) -> String {
         let (linenum, span) = self.location.clone().into();
-        let max_len = Self::calc_len(linenum.saturating_add(Self::VIEW_SIZE + 1));
+        let (header, color) = match self.severity {
+            Severity::Error => ("error", COLOR_RED),
+            Severity::Warning => ("warning", COLOR_YELLOW),
+            Severity::Note => ("note", COLOR_BLUE),
+        };
+        let spans: Vec<(&Location, char, &str, &str)> =
+            std::iter::once((&self.location, '^', color, self.message.as_str()))
+                .chain(
+                    self.notes
+                        .iter()
+                        .map(|n| (&n.location, '-', COLOR_BLUE, n.value.as_str())),
+                )
+                .collect();
+        let in_view = |i: usize| {
+            spans.iter().any(|(loc, ..)| {
+                i >= loc.0.saturating_sub(Self::VIEW_SIZE)
+                    && i <= loc.0.saturating_add(Self::VIEW_SIZE)
+            })
+        };
+        let max_line = spans.iter().map(|(loc, ..)| loc.0).max().unwrap_or(linenum);
+        let max_len = Self::calc_len(max_line.saturating_add(Self::VIEW_SIZE + 1));
 
         let mut out = String::new();
+        let mut prev_line = None;
         for (i, line) in self.code.lines().enumerate() {
-            if i < linenum.saturating_sub(Self::VIEW_SIZE)
-                || i > linenum.saturating_add(Self::VIEW_SIZE)
-            {
+            if !in_view(i) {
                 continue;
             }
+            if prev_line.is_some_and(|prev| prev + 1 != i) {
+                out.push_str("...\n");
+            }
+            prev_line = Some(i);
             Self::write_gutter(&mut out, Some(i), max_len);
             out.push_str(line);
             out.push('\n');
-            if i == linenum {
-                Self::write_gutter(&mut out, None, max_len);
-                for _ in 0..span.start {
-                    out.push(' ');
-                }
-                out.push_str(COLOR_RED);
-                let count = (span.end - span.start).max(1);
-                for _ in 0..count {
-                    out.push('^');
+            for (loc, marker, color, message) in spans.iter() {
+                if loc.0 == i {
+                    Self::write_gutter(&mut out, None, max_len);
+                    Self::write_span(&mut out, loc.1.clone(), *marker, color, message);
                 }
-                out.push(' ');
-                out.push_str(&self.message);
-                out.push_str(RESET);
-                out.push('\n');
             }
         }
-        eprintln!(
-            "{COLOR_RED}{BOLD}error:{RESET} {}:{}:{}\n{}",
+        format!(
+            "{color}{BOLD}{header}:{RESET} {}:{}:{}\n{}",
             self.filename, linenum, span.start, out
         )
     }
+
+    pub fn print(&self) {
+        eprintln!("{}", self.render())
+    }
 }
 pub trait IntoAsmError {
     fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a>;
 }
 
-fn io_to_asm_err<'a>(
-    io: std::io::Error,
-    location: Location,
-    code: &'a str,
-    filename: Rc<str>,
-) -> AsmError<'a> {
-    let message = match io.kind() {
-        std::io::ErrorKind::NotFound => "File not found".to_string(),
-        _ => {
-            format!("io error: {}", io)
+/// A file used by the program couldn't be read
+#[derive(Debug)]
+pub struct IncludeError {
+    pub path: PathBuf,
+    /// Where the file was searched
+    pub searched: Vec<PathBuf>,
+    pub error: io::Error,
+}
+impl IntoAsmError for Located<IncludeError> {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a> {
+        let message = match self.value.error.kind() {
+            io::ErrorKind::NotFound => format!(
+                "File not found: '{}' (searched: {})",
+                self.value.path.display(),
+                self.value
+                    .searched
+                    .iter()
+                    .map(|path| format!("'{}'", path.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            _ => format!(
+                "Failed to read '{}': {}",
+                self.value.path.display(),
+                self.value.error
+            ),
+        };
+        AsmError::new(filename, code, self.location, message)
+    }
+}
+
+/// Everything that stops a program from being assembled
+#[derive(Debug)]
+pub enum Error {
+    Lex(Located<LexError>),
+    Include(Located<IncludeError>),
+    CodeGen(Located<CodeGenError>),
+}
+impl Error {
+    pub fn location(&self) -> &Location {
+        match self {
+            Error::Lex(err) => &err.location,
+            Error::Include(err) => &err.location,
+            Error::CodeGen(err) => &err.location,
+        }
+    }
+}
+impl IntoAsmError for Error {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a> {
+        match self {
+            Error::Lex(err) => err.into_asm_error(code, filename),
+            Error::Include(err) => err.into_asm_error(code, filename),
+            Error::CodeGen(err) => err.into_asm_error(code, filename),
+        }
+    }
+}
+
+/// Something worth telling the user about a program that was assembled
+#[derive(Debug)]
+pub enum Diagnostic {
+    Lex(Located<LexWarning>),
+    CodeGen(Located<CodeGenWarning>),
+    Lint(Located<Lint>),
+    Optimization(Located<Optimization>),
+}
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Diagnostic::Lex(_) | Diagnostic::CodeGen(_) | Diagnostic::Lint(_) => Severity::Warning,
+            Diagnostic::Optimization(_) => Severity::Note,
+        }
+    }
+
+    pub fn location(&self) -> &Location {
+        match self {
+            Diagnostic::Lex(diag) => &diag.location,
+            Diagnostic::CodeGen(diag) => &diag.location,
+            Diagnostic::Lint(diag) => &diag.location,
+            Diagnostic::Optimization(diag) => &diag.location,
+        }
+    }
+}
+impl IntoAsmError for Diagnostic {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a> {
+        match self {
+            Diagnostic::Lex(diag) => diag.into_asm_error(code, filename),
+            Diagnostic::CodeGen(diag) => diag.into_asm_error(code, filename),
+            Diagnostic::Lint(diag) => diag.into_asm_error(code, filename),
+            Diagnostic::Optimization(diag) => diag.into_asm_error(code, filename),
         }
-    };
-    AsmError {
-        filename,
-        code,
-        location: location,
-        message,
     }
 }
 
-fn resolve_includes<'a>(
-    tokens: &mut Vec<Located<lex::Token>>,
-    code: &'a str,
-    filename: Rc<str>,
-) -> Result<(), AsmError<'a>> {
+/// How a program is assembled
+pub struct Options<'a> {
+    /// The instruction set used until a `.arch` directive
+    pub arch: Architecture,
+    /// Symbols that can be used in expressions. (like the counter of a `.rept`)
+    pub defines: Symbols,
+    /// The path of the source. Included files are searched next to it (or in the working directory when not set)
+    pub path: Option<PathBuf>,
+    /// Directories that are searched for included files that aren't found next to the source
+    pub include_paths: Vec<PathBuf>,
+    pub files: &'a dyn FileProvider,
+    /// The image the program is written on top of. (empty to start from zeroed banks)
+    pub base: Vec<Bank>,
+    /// Run the peephole optimizer
+    pub optimize: bool,
+    /// Check the program for likely mistakes
+    pub lint: bool,
+}
+impl Options<'_> {
+    pub fn new(arch: Architecture) -> Self {
+        Self {
+            arch,
+            defines: Symbols::new(),
+            path: None,
+            include_paths: Vec::new(),
+            files: &FsFiles,
+            base: Vec::new(),
+            optimize: false,
+            lint: true,
+        }
+    }
+
+    /// The paths an included file is searched at, in order
+    pub fn include_candidates(&self, path: &Path) -> Vec<PathBuf> {
+        if path.is_absolute() {
+            return vec![path.to_path_buf()];
+        }
+        let dir = self.path.as_deref().and_then(Path::parent);
+        std::iter::once(dir.unwrap_or(Path::new("")))
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(path))
+            .collect()
+    }
+
+    /// Reads an included file from the first place it is found at (and returns where that was)
+    fn read_include(&self, path: &Path) -> io::Result<(PathBuf, Vec<u8>)> {
+        let mut result = Err(io::Error::from(io::ErrorKind::NotFound));
+        for candidate in self.include_candidates(path) {
+            result = self.files.read(&candidate).map(|data| (candidate, data));
+            if !matches!(&result, Err(err) if err.kind() == io::ErrorKind::NotFound) {
+                break;
+            }
+        }
+        result
+    }
+}
+
+/// Replaces included files with their content and returns the paths that were read
+fn resolve_includes(
+    tokens: &mut [Located<lex::Token>],
+    options: &Options,
+) -> Result<Vec<PathBuf>, Located<IncludeError>> {
+    let mut includes = Vec::new();
     for token in tokens.iter_mut() {
-        match &token.value {
-            lex::Token::IncludeBytes(path) => {
-                let mut file = File::open(path).map_err(|e| {
-                    io_to_asm_err(e, token.location.clone(), code, filename.clone())
-                })?;
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer).map_err(|e| {
-                    io_to_asm_err(e, token.location.clone(), code, filename.clone())
-                })?;
-                let _ = std::mem::replace(
-                    token,
-                    Located::new(lex::Token::Bytes(buffer), token.location.clone()),
-                );
+        if let lex::Token::IncludeBytes(path) = &token.value {
+            let (found, buffer) = options.read_include(path).map_err(|error| {
+                Located::new(
+                    IncludeError {
+                        path: path.clone(),
+                        searched: options.include_candidates(path),
+                        error,
+                    },
+                    token.location.clone(),
+                )
+            })?;
+            if !includes.contains(&found) {
+                includes.push(found);
             }
-            _ => {}
+            let _ = std::mem::replace(
+                token,
+                Located::new(lex::Token::Bytes(buffer), token.location.clone()),
+            );
         }
     }
-    Ok(())
+    Ok(includes)
 }
 
-pub fn assemble(filename: Rc<str>, input: &str, arch: Architecture) -> Result<Vec<Bank>, AsmError> {
+pub fn assemble(input: &str, options: &Options) -> Result<Program, Error> {
+    let mut warnings = Vec::new();
     let mut parsed =
-        parse_lex(input, arch).map_err(|lex| lex.into_asm_error(&input, filename.clone()))?;
-    resolve_includes(&mut parsed, &input, filename.clone())?;
-    Ok(codegen::gen(parsed).map_err(|cg| cg.into_asm_error(&input, filename.clone()))?)
+        parse_lex(input, options.arch, &options.defines, &mut warnings).map_err(Error::Lex)?;
+    let includes = resolve_includes(&mut parsed, options).map_err(Error::Include)?;
+    let mut optimizations = Vec::new();
+    if options.optimize {
+        (parsed, optimizations) = peephole::optimize(parsed, options.arch);
+    }
+    let mut program =
+        codegen::gen(parsed, options.arch, options.base.clone()).map_err(Error::CodeGen)?;
+    if options.optimize {
+        peephole::check_branches(&program).map_err(Error::CodeGen)?;
+    }
+    program.includes = includes;
+    program
+        .diagnostics
+        .splice(0..0, warnings.into_iter().map(Diagnostic::Lex));
+    program
+        .diagnostics
+        .extend(optimizations.into_iter().map(Diagnostic::Optimization));
+    if options.lint {
+        let lints = lint::lint(&cfg::Cfg::new(&program));
+        program
+            .diagnostics
+            .extend(lints.into_iter().map(Diagnostic::Lint));
+    }
+    Ok(program)
 }
 
 #[cfg(test)]
 mod tests {
     use libnna::Architecture;
 
-    use super::Located;
+    use super::{IntoAsmError, Located, Options};
 
     fn assemble_assert_arch(code: &str, bin: Vec<&[u8]>, arch: Architecture) {
         let mut banks = Vec::new();
@@ -232,8 +479,9 @@ mod tests {
             full_bin[..bin.len()].copy_from_slice(bin);
             banks.push(full_bin);
         }
-        match super::assemble("test".into(), code, arch) {
-            Ok(gen_bin) => {
+        match super::assemble(code, &Options::new(arch)) {
+            Ok(program) => {
+                let gen_bin = program.banks;
                 if gen_bin != banks {
                     eprintln!("generated: (hex)");
                     for bank in gen_bin {
@@ -243,12 +491,12 @@ mod tests {
                     for bank in banks {
                         eprintln!("{:02X?}, ", bank);
                     }
-                    assert!(false);
+                    panic!("generated binary doesn't match");
                 }
             }
             Err(e) => {
-                e.print();
-                assert!(false)
+                e.into_asm_error(code, "test".into()).print();
+                panic!("assembling failed")
             }
         }
     }
@@ -258,9 +506,14 @@ mod tests {
     }
 
     fn assemble_assert_err(code: &str, err: Located<&str>) {
-        match super::assemble("test".into(), &code, Architecture::Nna8v1) {
-            Ok(_) => assert!(false, "An error should be thrown. but isn't"),
+        assemble_assert_err_arch(code, err, Architecture::Nna8v1)
+    }
+
+    fn assemble_assert_err_arch(code: &str, err: Located<&str>, arch: Architecture) {
+        match super::assemble(code, &Options::new(arch)) {
+            Ok(_) => panic!("An error should be thrown. but isn't"),
             Err(e) => {
+                let e = e.into_asm_error(code, "test".into());
                 assert_eq!(e.message, err.value, "error message doesn't match");
                 assert_eq!(e.location, err.location, "error location doesn't match");
             }
@@ -275,10 +528,6 @@ lih 0x2
 lil 0x4
 mov r1 r0"#;
 
-        let mut bin = [0; 256];
-        bin[0] = 0x22;
-        bin[1] = 0x14;
-        bin[2] = 0x54;
         assemble_assert(code, vec![&[0x22, 0x14, 0x54]]);
     }
 
@@ -299,6 +548,149 @@ nop
         );
     }
 
+    #[test]
+    fn adjacent_orgs() {
+        let code = r#".org 0x10
+nop
+nop
+.org 0x12
+brk
+.org 0x0E
+brk
+brk
+        "#;
+        assemble_assert(
+            code,
+            vec![&[
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x04, 0x04, 0x00, 0x00, 0x04,
+            ]],
+        );
+    }
+
+    #[test]
+    fn base_image() {
+        let mut base = [0; 256];
+        base[..4].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        let code = r#".org 0x01
+brk
+.org 0x04
+brk
+        "#;
+        let options = Options {
+            base: vec![base],
+            ..Options::new(Architecture::Nna8v1)
+        };
+        let program = super::assemble(code, &options).unwrap();
+        assert_eq!(program.banks.len(), 1);
+        assert_eq!(program.banks[0][..6], [0x11, 0x04, 0x33, 0x44, 0x04, 0x00]);
+
+        assert_eq!(program.diagnostics.len(), 1);
+        let super::Diagnostic::CodeGen(warning) = &program.diagnostics[0] else {
+            panic!("expected a codegen warning");
+        };
+        assert_eq!(warning.location, (0, 0..9).into());
+        assert!(matches!(
+            warning.value,
+            super::codegen::CodeGenWarning::BaseOverwritten(_, 1)
+        ));
+    }
+
+    #[test]
+    fn options() {
+        let mut files = super::files::MemoryFiles::new();
+        files.insert("assets/data.bin", [0xAB, 0xCD]);
+        let code = ".org 0x10\n.include_bytes \"data.bin\"\nVALUE\n";
+        let mut options = Options {
+            files: &files,
+            defines: [("VALUE".into(), 0x12)].into_iter().collect(),
+            ..Options::new(Architecture::Nna8v1)
+        };
+        let err = super::assemble(code, &options).unwrap_err();
+        assert!(matches!(err, super::Error::Include(_)));
+        assert_eq!(err.location(), &(1, 0..25).into());
+
+        options.include_paths.push("assets".into());
+        let program = super::assemble(code, &options).unwrap();
+        assert_eq!(program.banks[0][0x10..0x13], [0xAB, 0xCD, 0x12]);
+        assert_eq!(program.includes, [std::path::Path::new("assets/data.bin")]);
+
+        // next to the source is searched first
+        files.insert("src/data.bin", [0x01]);
+        let options = Options {
+            files: &files,
+            path: Some("src/main.asm".into()),
+            include_paths: vec!["assets".into()],
+            ..Options::new(Architecture::Nna8v1)
+        };
+        let program =
+            super::assemble(".org 0x00\n.include_bytes \"data.bin\"\n", &options).unwrap();
+        assert_eq!(program.banks[0][..2], [0x01, 0x00]);
+        assert_eq!(program.includes, [std::path::Path::new("src/data.bin")]);
+    }
+
+    #[test]
+    fn align() {
+        let code = r#".org 0x0E
+brk
+.align 0x10
+brk
+.align 0x4
+.align 0x4
+brk
+        "#;
+        let mut bin = [0; 0x16];
+        bin[0x0E] = 0x04;
+        bin[0x10] = 0x04;
+        bin[0x14] = 0x04;
+        assemble_assert(code, vec![&bin[..0x15]]);
+
+        assemble_assert_err(
+            ".org 0x00\n.align 0x3",
+            Located::new("Alignment has to be a power of 2.", (1, 7..10).into()),
+        );
+    }
+
+    #[test]
+    fn fill() {
+        let code = r#".org 0x00
+.fill 0x3, 0xAB
+.space 0x2
+.fill (2) 0x1
+0xFF
+        "#;
+        assemble_assert(
+            code,
+            vec![&[0xAB, 0xAB, 0xAB, 0x00, 0x00, 0x01, 0x01, 0xFF]],
+        );
+    }
+
+    #[test]
+    fn fill_out_of_bounds() {
+        assemble_assert_err(
+            ".org 0x00\n.space 0x101",
+            Located::new(
+                "This adds 0x101 bytes but only 0x100 are left in the bank.",
+                (1, 0..12).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0xF0\nnop\n.fill 0x10 0xAA\nend:",
+            Located::new(
+                "This adds 0x10 bytes but only 0x0f are left in the bank.",
+                (2, 0..15).into(),
+            ),
+        );
+        assemble_assert_err(
+            // aligning always fits in the bank, but the org can't reach its end
+            ".org 0x81\n.align 0x100",
+            Located::new(
+                "This org (size: 0x7f) extends past the bounds of the bank it is in.",
+                (0, 0..9).into(),
+            ),
+        );
+    }
+
     #[test]
     fn multi_bank_org() {
         let code = r#".org 0x10
@@ -405,6 +797,460 @@ nop
         );
     }
 
+    #[test]
+    fn local_labels() {
+        let code = r#".org 0x00
+first:
+nop
+.loop:
+&.loop
+second:
+.loop:
+&.loop
+&first.loop
+        "#;
+        assemble_assert(code, vec![&[0x00, 0x01, 0x02, 0x01]]);
+
+        assemble_assert_err(
+            ".org 0x00\n.loop:",
+            Located::new(
+                "local label '.loop' needs a global label before it",
+                (1, 0..6).into(),
+            ),
+        );
+    }
+
+    #[test]
+    fn anonymous_labels() {
+        let code = r#".org 0x00
+-:
+&+
+&++
++:
+&-
+-:
++:
+&-
+&--
+&+
++:
+"#;
+        assemble_assert(code, vec![&[0x02, 0x03, 0x00, 0x03, 0x00, 0x06]]);
+
+        assemble_assert_err(
+            ".org 0x00\n&-",
+            Located::new("label '-' is not defined", (1, 0..2).into()),
+        );
+        assemble_assert_err(
+            ".org 0x00\n&+",
+            Located::new("label '+' is not defined", (1, 0..2).into()),
+        );
+    }
+
+    #[test]
+    fn shadowing() {
+        let code = r#"
+.org 0x00
+loop:
+start:
+.loop:
+.rept 0x2 i
+.rept 0x2 i
+i
+.endr
+.endr
+"#;
+        let program = super::assemble(code, &Options::new(Architecture::Nna8v1)).unwrap();
+        let warnings: Vec<_> = program
+            .diagnostics
+            .into_iter()
+            .map(|diag| diag.into_asm_error(code, "test".into()))
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].message,
+            "The counter 'i' hides a define or outer counter with the same name"
+        );
+        assert_eq!(warnings[0].location, (6, 0..11).into());
+        assert_eq!(
+            warnings[1].message,
+            "local label '.loop' has the name of the global label 'loop'"
+        );
+        assert_eq!(warnings[1].location, (4, 0..6).into());
+        assert_eq!(warnings[1].notes[0].location, (2, 0..5).into());
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let code = r#".org 0x00
+label:
+nop
+label:
+"#;
+        assemble_assert_err(
+            code,
+            Located::new("label 'label' is already defined", (3, 0..6).into()),
+        );
+
+        let err = super::assemble(code, &Options::new(Architecture::Nna8v1))
+            .unwrap_err()
+            .into_asm_error(code, "test".into());
+        assert_eq!(
+            err.notes,
+            vec![Located::new(
+                "first defined here".to_string(),
+                (1, 0..6).into()
+            )]
+        );
+        let rendered = err.render();
+        assert!(rendered.contains("^^^^^^ label 'label' is already defined"));
+        assert!(rendered.contains("------ first defined here"));
+    }
+
+    #[test]
+    fn func_call() {
+        let code = r#".org 0x00
+call func
+brk
+.func func
+ret
+.endfunc
+"#;
+        assemble_assert(
+            code,
+            vec![&[
+                0x18, 0x20, 0x5C, 0x17, 0x20, 0x0C, 0x0D, 0x04, 0x5C, 0x11, 0x21, 0x3C, 0x11, 0x21,
+                0x40, 0x0C, 0x01, 0x00,
+            ]],
+        );
+    }
+
+    #[test]
+    fn func_slot_bank() {
+        let code = r#".arch "nna8v2"
+.org 0x00
+.func f &slot 0x80
+ret
+.endfunc
+slot:
+"#;
+        assemble_assert_arch(
+            code,
+            vec![&[
+                0xBC, 0x90, 0xA8, 0x03, 0x9F, 0xA0, 0x7C, 0x90, 0xA8, 0x03, 0x9F, 0xA0, 0x80, 0x34,
+                0x01,
+            ]],
+            Architecture::Nna8v2,
+        );
+    }
+
+    #[test]
+    fn func_stack() {
+        let code = r#".org 0x00
+.stack &sp 0x00 0xee
+call f
+brk
+.func f
+ret
+.endfunc
+sp: 0x00
+"#;
+        let expanded = r#".org 0x00
+lil &f.low
+lih &f.high
+mov r3 r0
+lil &back.low
+lih &back.high
+clf
+jmp r3
+back:
+brk
+f:
+mov r3 r0
+lil &sp.low
+lih &sp.high
+mrd r0 r0
+dec r0
+mwr r3 r0
+mov r3 r0
+lil &sp.low
+lih &sp.high
+mwr r3 r0
+lil &sp.low
+lih &sp.high
+mrd r3 r0
+inc r3
+mwr r3 r0
+dec r3
+mrd r0 r3
+clf
+jmp r0
+sp: 0x00
+"#;
+        let options = Options::new(Architecture::Nna8v1);
+        assert_eq!(
+            super::assemble(code, &options).unwrap().banks,
+            super::assemble(expanded, &options).unwrap().banks
+        );
+    }
+
+    #[test]
+    fn func_local_ret_label() {
+        let code = r#".org 0x00
+.func f
+.ret:
+bra &.ret.low
+ret
+.endfunc
+"#;
+        assert!(super::assemble(code, &Options::new(Architecture::Nna8v1)).is_ok());
+    }
+
+    #[test]
+    fn func_errors() {
+        assemble_assert_err(
+            ".org 0x00\nret",
+            Located::new("ret can only be used inside a .func", (1, 0..3).into()),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.func a\n.func b",
+            Located::new(
+                "Functions can't be nested. (missing .endfunc?)",
+                (2, 0..7).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.func a\nnop",
+            Located::new("This .func is missing an .endfunc", (1, 0..7).into()),
+        );
+        assemble_assert_err_arch(
+            ".arch \"nna8v2\"\n.org 0x00\n.func a\nret\n.endfunc",
+            Located::new(
+                "Bank 0x00 can't be written so this function needs a return address slot in RAM (.func a &slot 0x80) or a .stack",
+                (2, 0..7).into(),
+            ),
+            Architecture::Nna8v2,
+        );
+        assemble_assert_err_arch(
+            ".arch \"nna8v2\"\n.org 0x00\n.func a &slot\nret\n.endfunc",
+            Located::new(
+                "Bank 0x00 can't be written so it can't hold the return address slot. Use a bank in RAM",
+                (2, 0..13).into(),
+            ),
+            Architecture::Nna8v2,
+        );
+        assemble_assert_err_arch(
+            ".arch \"nna8v2\"\n.org 0x00\n.stack &sp 0xFF",
+            Located::new(
+                "Bank 0xff can't be written so it can't hold the stack. Use a bank in RAM",
+                (2, 0..15).into(),
+            ),
+            Architecture::Nna8v2,
+        );
+        // 0xee and up are the keyboard and screen on nna8v1
+        assemble_assert_err(
+            ".org 0x00\n.stack &sp",
+            Located::new(
+                "The stack starts at 0xff but only 0x00..0xee of bank 0x00 is memory",
+                (1, 0..10).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.stack &sp 0x00 0xef",
+            Located::new(
+                "The stack starts at 0xee but only 0x00..0xee of bank 0x00 is memory",
+                (1, 0..20).into(),
+            ),
+        );
+    }
+
+    #[test]
+    fn rept() {
+        let code = r#"
+.org 0x00
+.rept 0x4 i
+(i*0x10+i)
+.endr
+.rept 0x2
+nop
+brk
+.endr
+"#;
+        assemble_assert(
+            code,
+            vec![&[0x00, 0x11, 0x22, 0x33, 0x00, 0x04, 0x00, 0x04]],
+        );
+    }
+
+    #[test]
+    fn rept_nested() {
+        let code = r#"
+.org 0x00
+.rept 0x2 y
+.rept 0x3 x
+(y*0x10+x)
+.endr
+.endr
+.rept 0x0
+nop
+.endr
+lil y
+"#;
+        assemble_assert_err(
+            code,
+            Located::new("Expected an 4 bit value.", (10, 4..5).into()),
+        );
+        assemble_assert(
+            &code.replace("lil y", ""),
+            vec![&[0x00, 0x01, 0x02, 0x10, 0x11, 0x12]],
+        );
+    }
+
+    #[test]
+    fn rept_reserved_counter() {
+        assemble_assert_err(
+            ".org 0x00\n.rept 0x2 nop\nnop\n.endr",
+            Located::new(
+                "'nop' is an instruction and can't be used as a counter name",
+                (1, 10..13).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.rept 0x2 r1\n.endr",
+            Located::new(
+                "'r1' is a register and can't be used as a counter name",
+                (1, 10..12).into(),
+            ),
+        );
+    }
+
+    #[test]
+    fn rept_missing_endr() {
+        let code = r#"
+.org 0x00
+.rept 0x2 i
+nop
+"#;
+        assemble_assert_err(
+            code,
+            Located::new("This .rept is missing an .endr", (2, 0..11).into()),
+        );
+    }
+
+    #[test]
+    fn aliases() {
+        let code = r#"
+.arch "nna8v2"
+.org 0x00
+.alias char r1
+.alias op sub
+.func print &slot 0x80
+.alias offset r3
+.alias index offset
+mov char index
+mco op
+.endfunc
+.unalias op
+slot:
+"#;
+        assemble_assert_arch(
+            code,
+            vec![&[0xBC, 0x90, 0xA8, 0x03, 0x99, 0xA0, 0x7C, 0xB7, 0x61]],
+            Architecture::Nna8v2,
+        );
+        assemble_assert_err(
+            &format!("{}mov offset r0", code),
+            Located::new(
+                "Invalid 'reg'. Expected one of: r0, r1, r2, r3",
+                (13, 4..10).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.alias op add\nmov op r0",
+            Located::new(
+                "'op' is an alias of 'add' which isn't a valid 'reg'",
+                (2, 4..6).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.alias r1 r2",
+            Located::new(
+                "An alias can't be named like a register or calculation operation",
+                (1, 7..9).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\n.unalias a",
+            Located::new("No alias with this name is defined", (1, 9..10).into()),
+        );
+
+        let program = super::assemble(
+            ".org 0x00\n.alias a r1\n.alias b r1\n.alias a r2\n.alias c r2\nbrk",
+            &Options::new(Architecture::Nna8v1),
+        )
+        .unwrap();
+        let warnings: Vec<_> = program
+            .diagnostics
+            .iter()
+            .filter_map(|diag| match diag {
+                super::Diagnostic::Lex(warning) => Some(warning),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        let super::LexWarning::SharedRegister(register, other) = &warnings[1].value else {
+            panic!("expected a shared register warning");
+        };
+        assert_eq!((*register, &*other.value), ("r2", "a"));
+        assert_eq!(warnings[1].location, (4, 0..11).into());
+    }
+
+    #[test]
+    fn suggestions() {
+        assemble_assert_err(
+            ".org 0x00\nmvo r1 r0",
+            Located::new("Unknown operation. Did you mean 'mov'?", (1, 0..3).into()),
+        );
+        assemble_assert_err(
+            ".ogr 0x00",
+            Located::new(
+                "Unknown compiler directive. Did you mean '.org'?",
+                (0, 0..4).into(),
+            ),
+        );
+        assemble_assert_err(
+            ".org 0x00\nmov r1 r5",
+            Located::new(
+                "Invalid 'reg'. Expected one of: r0, r1, r2, r3",
+                (1, 7..9).into(),
+            ),
+        );
+
+        let code = ".org 0x00\nmov r1";
+        let err = super::assemble(code, &Options::new(Architecture::Nna8v1))
+            .err()
+            .unwrap()
+            .into_asm_error(code, "test".into());
+        assert_eq!(err.notes[0].value, "expected `mov [dest:reg] [source:reg]`");
+        assert_eq!(err.notes[0].location, (1, 0..3).into());
+    }
+
+    #[test]
+    fn directives_recognized() {
+        // the arguments are missing so most fail, but never as an unknown directive
+        for (name, _) in super::DIRECTIVES {
+            let code = format!(".org 0x00\n.{}\n", name);
+            if let Err(err) = super::assemble(&code, &Options::new(Architecture::Nna8v2)) {
+                let err = err.into_asm_error(&code, "test".into());
+                assert!(
+                    !err.message.starts_with("Unknown compiler directive"),
+                    ".{}",
+                    name
+                );
+            }
+        }
+    }
+
     mod instruction {
         use super::{assemble_assert, assemble_assert_arch};
         use libnna::Architecture;
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 
 use super::{Located, Location};
 
+#[derive(Clone)]
 struct CodeIter<'a> {
     code: &'a str,
     index: usize,
@@ -59,6 +60,7 @@ impl<'a> CodeIter<'a> {
     }
 }
 
+#[derive(Clone)]
 pub struct Parser<'a> {
     last_location: Location,
     codeiter: CodeIter<'a>,
@@ -85,9 +87,6 @@ impl<'a> Parser<'a> {
         Some(char)
     }
 
-    pub fn code(&self) -> &'a str {
-        self.codeiter.code()
-    }
     pub fn location(&self) -> Location {
         self.last_location.clone()
     }
@@ -0,0 +1,318 @@
+//! Optional rewrites of short instruction sequences that do the same thing with fewer instructions
+
+use std::rc::Rc;
+
+use libnna::{Architecture, ControlFlow, FlagEffect, Register};
+
+use super::{
+    cfg::{self, Regs},
+    codegen::{Addr, CodeGenError},
+    lex::{OpToken, Token},
+    AsmError, IntoAsmError, Located, Program,
+};
+
+/// A change made by the optimizer
+#[derive(Debug, PartialEq, Eq)]
+pub enum Optimization {
+    /// An instruction that doesn't change anything was removed. (contains the reason)
+    Removed(&'static str),
+    /// An `inc` or `dec` was merged into the one before it. (contains the total amount)
+    Merged(&'static str, u8),
+}
+impl IntoAsmError for Located<Optimization> {
+    fn into_asm_error<'a>(self, code: &'a str, filename: Rc<str>) -> AsmError<'a> {
+        let message = match self.value {
+            Optimization::Removed(reason) => format!("removed ({})", reason),
+            Optimization::Merged(name, amount) => {
+                format!("merged into the {} before (by {:#x} now)", name, amount)
+            }
+        };
+        AsmError::note(filename, code, self.location, message)
+    }
+}
+
+/// Applies the optimizations to the tokens from the lexer.
+/// Instructions directly after a label or before a `.reachable` are never changed.
+pub(super) fn optimize(
+    tokens: Vec<Located<Token>>,
+    mut arch: Architecture,
+) -> (Vec<Located<Token>>, Vec<Located<Optimization>>) {
+    let pinned: Vec<bool> = (0..tokens.len())
+        .map(|i| {
+            matches!(
+                i.checked_sub(1).map(|i| &tokens[i].value),
+                Some(Token::LabelDef(_))
+            ) || matches!(
+                tokens.get(i + 1).map(|t| &t.value),
+                Some(Token::Reachable(_))
+            )
+        })
+        .collect();
+    let flag_dead = flag_dead(&tokens, arch);
+
+    let mut out: Vec<Located<Token>> = Vec::with_capacity(tokens.len());
+    let mut changes = Vec::new();
+    let mut regs = Regs::default();
+    let mut flag: Option<bool> = None;
+    // the last token in `out` if it is an instruction that can be merged into
+    let mut last_op: Option<u8> = None;
+    for ((token, pinned), flag_dead) in tokens.into_iter().zip(pinned).zip(flag_dead) {
+        let byte = match token.value {
+            Token::Op(OpToken::Full(byte)) => byte,
+            Token::Op(OpToken::LabelRef(byte, _, _)) => {
+                if let Some((_, _, effects, _)) = cfg::decode(arch, byte) {
+                    regs.clobber(byte, effects.writes);
+                }
+                out.push(token);
+                last_op = None;
+                continue;
+            }
+            _ => {
+                if let Token::Arch(a) = token.value {
+                    arch = a;
+                }
+                // code after labels and directives can be reached from elsewhere
+                regs = Regs::default();
+                flag = None;
+                out.push(token);
+                last_op = None;
+                continue;
+            }
+        };
+        let Some((name, _, effects, args)) = cfg::decode(arch, byte) else {
+            regs = Regs::default();
+            flag = None;
+            out.push(token);
+            last_op = None;
+            continue;
+        };
+
+        let a = Register::A.gpr(byte).unwrap();
+        let b = Register::B.gpr(byte).unwrap();
+        let removed = match name {
+            "mov" if a == b => Some("moves a register into itself"),
+            "lil" if regs.nibble(0, 0) == Some(byte & 0x0F) => {
+                Some("r0 already has this low nibble")
+            }
+            "lih" if regs.nibble(0, 1) == Some(byte & 0x0F) => {
+                Some("r0 already has this high nibble")
+            }
+            _ => match (effects.flag, flag) {
+                (FlagEffect::Clear, Some(false)) => Some("the flag is already clear"),
+                (FlagEffect::Set, Some(true)) => Some("the flag is already set"),
+                _ => None,
+            },
+        };
+        // inc and dec only take an amount on nna8v2.
+        // The flag of the merged instruction misses a wrap of the first one, so it has to be unused
+        let merged = match (last_op, arch) {
+            (Some(prev), Architecture::Nna8v2)
+                if matches!(name, "inc" | "dec") && prev & 0xFC == byte & 0xFC && flag_dead =>
+            {
+                let amount = (prev & 0x03) + (byte & 0x03) + 2;
+                (amount <= 4).then_some(amount)
+            }
+            _ => None,
+        };
+
+        regs.step(name, byte, Some(args), effects.writes);
+        flag = match effects.flag {
+            FlagEffect::Set => Some(true),
+            FlagEffect::Clear => Some(false),
+            FlagEffect::Flip => flag.map(|f| !f),
+            FlagEffect::Write => None,
+            FlagEffect::None | FlagEffect::Consume => flag,
+        };
+
+        match (removed, merged) {
+            (Some(reason), _) if !pinned => {
+                changes.push(Located::new(Optimization::Removed(reason), token.location));
+            }
+            (_, Some(amount)) if !pinned => {
+                let prev = out.last_mut().unwrap();
+                prev.value = Token::Op(OpToken::Full(byte & 0xFC | (amount - 1)));
+                changes.push(Located::new(
+                    Optimization::Merged(name, amount),
+                    token.location,
+                ));
+                last_op = (!pinned).then_some(byte & 0xFC | (amount - 1));
+            }
+            _ => {
+                out.push(token);
+                last_op = (!pinned).then_some(byte);
+            }
+        }
+    }
+    (out, changes)
+}
+
+/// Checks that every branch of the optimized program is still on the page of its label.
+/// Removing or merging instructions moves the code after them, which can put a branch and its target on different pages.
+pub(super) fn check_branches(program: &Program) -> Result<(), Located<CodeGenError>> {
+    let page = |addr: Addr| (addr.bank, addr.addr & 0xF0);
+    for inst in &program.instructions {
+        let byte = program.banks[inst.value.bank as usize][inst.value.addr as usize];
+        let branches = cfg::decode(program.arch, byte)
+            .is_some_and(|(_, _, effects, _)| effects.control == ControlFlow::Branch);
+        let label = program
+            .references
+            .iter()
+            .find(|reference| reference.location == inst.location);
+        let target = label.and_then(|label| {
+            program
+                .symbols
+                .iter()
+                .find(|symbol| symbol.value.name == label.value)
+        });
+        match target {
+            Some(target) if branches && page(target.value.addr) != page(inst.value) => {
+                return Err(Located::new(
+                    CodeGenError::BranchOffPage(
+                        target.value.display_name().into(),
+                        target.location.clone(),
+                    ),
+                    inst.location.clone(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// If the flag after each token is overwritten before anything reads it.
+/// Labels, directives and jumps end the search because the flag could be read elsewhere.
+fn flag_dead(tokens: &[Located<Token>], mut arch: Architecture) -> Vec<bool> {
+    let arches: Vec<Architecture> = tokens
+        .iter()
+        .map(|token| {
+            if let Token::Arch(a) = token.value {
+                arch = a;
+            }
+            arch
+        })
+        .collect();
+
+    let mut dead = vec![false; tokens.len()];
+    let mut next_dead = false;
+    for (i, token) in tokens.iter().enumerate().rev() {
+        dead[i] = next_dead;
+        let decoded = match token.value {
+            Token::Op(OpToken::Full(byte) | OpToken::LabelRef(byte, _, _)) => {
+                cfg::decode(arches[i], byte)
+            }
+            _ => None,
+        };
+        next_dead = match decoded {
+            Some((_, _, effects, _)) => match effects.flag {
+                FlagEffect::Set | FlagEffect::Clear | FlagEffect::Write => true,
+                FlagEffect::Flip | FlagEffect::Consume => false,
+                FlagEffect::None => next_dead && effects.control == ControlFlow::Next,
+            },
+            None => false,
+        };
+    }
+    dead
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::Optimization;
+    use crate::asm::{assemble, codegen::CodeGenError, Diagnostic, Error, Options};
+
+    fn optimize(code: &str) -> (Vec<u8>, Vec<(usize, Optimization)>) {
+        let options = Options {
+            optimize: true,
+            lint: false,
+            ..Options::new(Architecture::Nna8v2)
+        };
+        let program = assemble(code, &options).unwrap();
+        let org = &program.orgs[0];
+        let bytes = program.banks[0][org.start_addr as usize..][..org.size as usize].to_vec();
+        let changes = program
+            .diagnostics
+            .into_iter()
+            .filter_map(|diagnostic| match diagnostic {
+                Diagnostic::Optimization(change) => Some(change),
+                _ => None,
+            })
+            .map(|change| {
+                let (line, _): (usize, _) = change.location.into();
+                (line, change.value)
+            })
+            .collect();
+        (bytes, changes)
+    }
+
+    #[test]
+    fn peephole() {
+        let (bytes, changes) = optimize(
+            ".org 0x00\ninc r1 0x1\ninc r1 0x1\nmov r0 r0\nxor r0 r0\nlil 0x5\nlih 0x0\nclf\nclf\n",
+        );
+        assert_eq!(bytes, [0xE5, 0xD0, 0x95, 0x34]);
+        assert_eq!(
+            changes,
+            [
+                (2, Optimization::Merged("inc", 2)),
+                (3, Optimization::Removed("moves a register into itself")),
+                (6, Optimization::Removed("r0 already has this high nibble")),
+                (8, Optimization::Removed("the flag is already clear")),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_flag() {
+        // the flag of the second inc is only the same as the merged one's when the first doesn't wrap
+        let (bytes, changes) = optimize(".org 0x00\ninc r1 0x1\ninc r1 0x1\nbra 0x0\n");
+        assert_eq!(bytes, [0xE4, 0xE4, 0x50]);
+        assert!(changes.is_empty());
+
+        let (bytes, changes) = optimize(".org 0x00\ndec r2 0x1\ndec r2 0x2\nmov r0 r2\n");
+        assert_eq!(bytes, [0xF8, 0xF9, 0xB2]);
+        assert!(changes.is_empty());
+
+        let (bytes, changes) = optimize(".org 0x00\ndec r2 0x1\ndec r2 0x2\nmov r0 r2\neq r0 r1\n");
+        assert_eq!(bytes, [0xFA, 0xB2, 0x11]);
+        assert_eq!(changes, [(2, Optimization::Merged("dec", 3))]);
+    }
+
+    #[test]
+    fn branch_pages() {
+        // the removed mov moves the bra from 0x10 to 0x0F while its target stays on page 0x10
+        let code = format!(
+            ".org 0x00\nmov r0 r0\n{}bra &target.low\nnop\ntarget: nop\n",
+            "nop\n".repeat(15)
+        );
+        let options = Options {
+            optimize: true,
+            ..Options::new(Architecture::Nna8v2)
+        };
+        match assemble(&code, &options) {
+            Err(Error::CodeGen(err)) => {
+                let (line, _) = err.location.into();
+                assert_eq!(line, 17);
+                assert!(
+                    matches!(err.value, CodeGenError::BranchOffPage(name, _) if &*name == "target")
+                );
+            }
+            other => panic!("expected an error but got {:?}", other.map(|_| ())),
+        }
+
+        // branches that stay on their page are fine
+        let (bytes, changes) = optimize(".org 0x00\nmov r0 r0\nbra &target.low\ntarget: nop\n");
+        assert_eq!(bytes, [0x51, 0x00]);
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn pinned() {
+        // labels and .reachable keep the instructions they belong to
+        let code = ".org 0x00\ninc r1 0x3\nlabel:\ninc r1 0x1\nclf\nclf\n.reachable 0x00\ndec r2 0x4\ndec r2 0x1\n";
+        let (bytes, changes) = optimize(code);
+        assert_eq!(bytes, [0xE6, 0xE4, 0x34, 0x34, 0xFB, 0xF8]);
+        assert!(changes.is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+use libnna::{
+    instruction_sets::{Nna8v1, Nna8v2},
+    Arch, Architecture, OpArgs,
+};
+
+use super::{
+    cfg::{self, Regs},
+    lex::{LexError, OpToken, RefType, Token, ValueToken},
+    Located, Location,
+};
+
+/// Encodes an instruction from its name and argument values.
+/// For instructions with one (4 bit) argument `b` is ignored.
+fn encode<I: Arch + Into<u8>>(name: &str, a: u8, b: u8) -> Option<u8> {
+    let op = I::try_from_str(name)?;
+    Some(match op.args() {
+        OpArgs::Arg(_) => op.into() | (a & 0x0F),
+        OpArgs::ArgArg(_, _) => op.into() | (a & 0x03) << 2 | (b & 0x03),
+    })
+}
+
+/// Builds the tokens a pseudo instruction or directive expands to.
+pub struct Expansion {
+    arch: Architecture,
+    location: Location,
+    pub tokens: Vec<Located<Token>>,
+}
+impl Expansion {
+    pub fn new(arch: Architecture, location: Location) -> Self {
+        Self {
+            arch,
+            location,
+            tokens: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, token: Token) {
+        self.tokens.push(Located::new(token, self.location.clone()));
+    }
+
+    fn encode(&self, name: &str, a: u8, b: u8) -> u8 {
+        match self.arch {
+            Architecture::Nna8v1 => encode::<Nna8v1>(name, a, b),
+            Architecture::Nna8v2 => encode::<Nna8v2>(name, a, b),
+        }
+        .unwrap_or_else(|| panic!("'{}' doesn't exist on {:?}", name, self.arch))
+    }
+
+    /// Emit a real instruction
+    pub fn op(&mut self, name: &str, a: u8, b: u8) {
+        let byte = self.encode(name, a, b);
+        self.push(Token::Op(OpToken::Full(byte)));
+    }
+
+    pub fn label(&mut self, name: Box<str>) {
+        self.push(Token::LabelDef(name));
+    }
+
+    pub fn byte(&mut self, value: u8) {
+        self.push(Token::Value(super::lex::ValueToken::Const(value)));
+    }
+
+    /// Load a constant into r0
+    pub fn load_const(&mut self, value: u8) {
+        self.op("lil", value & 0x0F, 0);
+        self.op("lih", value >> 4, 0);
+    }
+
+    /// Emit an instruction with a 4 bit label reference as the argument
+    fn op_ref(&mut self, name: &str, label: &str, ty: RefType) {
+        let op = self.encode(name, 0, 0);
+        self.push(Token::Op(OpToken::LabelRef(op, label.into(), ty)));
+    }
+
+    /// Load the address of a label into r0
+    pub fn load_label(&mut self, label: &str) {
+        self.op_ref("lil", label, RefType::Low);
+        self.op_ref("lih", label, RefType::High);
+    }
+
+    /// Point the data bank at `bank`. (only does something on archs with banks)
+    pub fn select_data_bank(&mut self, bank: u8) {
+        if self.arch == Architecture::Nna8v2 {
+            self.load_const(bank);
+            self.op("mdb", 0, 0);
+        }
+    }
+}
+
+/// A software stack for return addresses set up with `.stack`
+#[derive(Clone)]
+pub struct Stack {
+    /// The label of the byte that holds the address of the last pushed return address
+    pub pointer: Box<str>,
+    /// The bank the pointer and the stack are in
+    pub bank: u8,
+}
+
+/// Where a function keeps its return address
+pub enum Return {
+    /// A single byte (emitted at `.endfunc` if `inline`)
+    Slot {
+        label: Box<str>,
+        bank: u8,
+        inline: bool,
+    },
+    Stack(Stack),
+}
+
+/// A function defined using `.func`
+pub struct Func {
+    pub name: Box<str>,
+    pub ret: Return,
+    pub location: Location,
+}
+impl Func {
+    /// A function with its own slot (the `#` keeps the label from colliding with ones in the source)
+    pub fn new(name: Box<str>, slot: Option<Box<str>>, bank: u8, location: Location) -> Self {
+        Self {
+            ret: Return::Slot {
+                inline: slot.is_none(),
+                label: slot.unwrap_or_else(|| format!("ret#{}", name).into()),
+                bank,
+            },
+            name,
+            location,
+        }
+    }
+
+    /// A function that pushes its return address onto a stack (so it can be called recursively)
+    pub fn with_stack(name: Box<str>, stack: Stack, location: Location) -> Self {
+        Self {
+            name,
+            ret: Return::Stack(stack),
+            location,
+        }
+    }
+
+    /// The label and the code that saves the return address (r0)
+    pub fn prologue(&self, arch: Architecture) -> Vec<Located<Token>> {
+        let mut exp = Expansion::new(arch, self.location.clone());
+        exp.label(self.name.clone());
+        exp.op("mov", 3, 0);
+        match &self.ret {
+            Return::Slot { label, bank, .. } => {
+                exp.select_data_bank(*bank);
+                exp.load_label(label);
+                exp.op("mwr", 3, 0);
+            }
+            Return::Stack(stack) => {
+                exp.select_data_bank(stack.bank);
+                exp.load_label(&stack.pointer);
+                exp.op("mrd", 0, 0);
+                exp.op("dec", 0, 0);
+                exp.op("mwr", 3, 0);
+                // store the new stack pointer
+                exp.op("mov", 3, 0);
+                exp.load_label(&stack.pointer);
+                exp.op("mwr", 3, 0);
+            }
+        }
+        exp.tokens
+    }
+
+    /// Jump back to the saved return address
+    pub fn ret(&self, arch: Architecture, location: Location) -> Vec<Located<Token>> {
+        let mut exp = Expansion::new(arch, location);
+        match &self.ret {
+            Return::Slot { label, bank, .. } => {
+                exp.select_data_bank(*bank);
+                exp.load_label(label);
+                exp.op("mrd", 0, 0);
+            }
+            Return::Stack(stack) => {
+                exp.select_data_bank(stack.bank);
+                exp.load_label(&stack.pointer);
+                exp.op("mrd", 3, 0);
+                exp.op("inc", 3, 0);
+                exp.op("mwr", 3, 0);
+                exp.op("dec", 3, 0);
+                exp.op("mrd", 0, 3);
+            }
+        }
+        exp.op("clf", 0, 0);
+        exp.op("jmp", 0, 0);
+        exp.tokens
+    }
+
+    /// Emits the slot if the function owns it
+    pub fn end(&self, arch: Architecture, location: Location) -> Vec<Located<Token>> {
+        let mut exp = Expansion::new(arch, location);
+        if let Return::Slot {
+            label,
+            inline: true,
+            ..
+        } = &self.ret
+        {
+            exp.label(label.clone());
+            exp.byte(0);
+        }
+        exp.tokens
+    }
+}
+
+/// Jump to `target` with the return address in r0. `index` is used to create a unique return label.
+pub fn call(
+    arch: Architecture,
+    target: &str,
+    index: usize,
+    location: Location,
+) -> Vec<Located<Token>> {
+    let ret_label: Box<str> = format!("call#{}", index).into();
+    let mut exp = Expansion::new(arch, location);
+    exp.load_label(target);
+    exp.op("mov", 3, 0);
+    exp.load_label(&ret_label);
+    exp.op("clf", 0, 0);
+    exp.op("jmp", 3, 0);
+    exp.label(ret_label);
+    exp.tokens
+}
+
+/// Load a value into a register (`li rN value`) using as few instructions as possible
+/// given the register values that are known before it.
+pub fn li(
+    arch: Architecture,
+    reg: u8,
+    value: ValueToken<u8>,
+    regs: &Regs,
+    location: Location,
+) -> Vec<Located<Token>> {
+    let mut exp = Expansion::new(arch, location);
+    let value = match value {
+        ValueToken::Const(value) => value,
+        ValueToken::LabelRef(label, RefType::Full) => {
+            exp.load_label(&label);
+            if reg != 0 {
+                exp.op("mov", reg, 0);
+            }
+            return exp.tokens;
+        }
+        ValueToken::LabelRef(label, ty) => {
+            exp.op_ref("lil", &label, ty);
+            if regs.nibble(0, 1) != Some(0) {
+                exp.op("lih", 0, 0);
+            }
+            if reg != 0 {
+                exp.op("mov", reg, 0);
+            }
+            return exp.tokens;
+        }
+    };
+
+    if regs.get(reg) == Some(value) {
+        return exp.tokens;
+    }
+    if let Some(src) = (0..4).find(|src| regs.get(*src) == Some(value)) {
+        exp.op("mov", reg, src);
+        return exp.tokens;
+    }
+
+    let lil = regs.nibble(0, 0) != Some(value & 0x0F);
+    let lih = regs.nibble(0, 1) != Some(value >> 4);
+    let count = lil as usize + lih as usize + (reg != 0) as usize;
+    // inc and dec change the flag so they are only used when they are shorter
+    if let (Some(current), true) = (regs.get(reg), count > 1) {
+        let max = match arch {
+            Architecture::Nna8v1 => 1,
+            Architecture::Nna8v2 => 4,
+        };
+        for (name, delta) in [
+            ("inc", value.wrapping_sub(current)),
+            ("dec", current.wrapping_sub(value)),
+        ] {
+            if delta <= max {
+                exp.op(name, reg, delta - 1);
+                return exp.tokens;
+            }
+        }
+    }
+
+    if lil {
+        exp.op("lil", value & 0x0F, 0);
+    }
+    if lih {
+        exp.op("lih", value >> 4, 0);
+    }
+    if reg != 0 {
+        exp.op("mov", reg, 0);
+    }
+    exp.tokens
+}
+
+/// Updates the known register values with the tokens emitted after the last call.
+/// Everything is forgotten at labels and directives because the code after them can be reached from elsewhere.
+pub fn track_regs(regs: &mut Regs, arch: Architecture, tokens: &[Located<Token>]) {
+    for token in tokens {
+        let byte = match &token.value {
+            Token::Op(OpToken::Full(byte) | OpToken::LabelRef(byte, _, _)) => *byte,
+            Token::Arch(_) | Token::Reachable(_) => continue,
+            _ => {
+                *regs = Regs::default();
+                continue;
+            }
+        };
+        let Some((name, _, effects, args)) = cfg::decode(arch, byte) else {
+            *regs = Regs::default();
+            continue;
+        };
+        match token.value {
+            // the value of the reference isn't known yet
+            Token::Op(OpToken::LabelRef(..)) => regs.clobber(byte, effects.writes),
+            _ => regs.step(name, byte, Some(args), effects.writes),
+        }
+    }
+}
+
+pub fn no_func_error(what: &'static str, location: Location) -> Located<LexError> {
+    LexError::located(
+        format!("{} can only be used inside a .func", what).into(),
+        location,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::{
+        instruction_sets::{Nna8v1, Nna8v2},
+        Architecture,
+    };
+
+    use super::{encode, li, track_regs};
+    use crate::asm::{
+        cfg::Regs,
+        lex::{OpToken, RefType, Token, ValueToken},
+        Located,
+    };
+
+    /// The bytes `li` expands to after `before`
+    fn expand(arch: Architecture, before: &[u8], reg: u8, value: ValueToken<u8>) -> Vec<Token> {
+        let tokens: Vec<_> = before
+            .iter()
+            .map(|byte| Located::new(Token::Op(OpToken::Full(*byte)), (0, 0..0).into()))
+            .collect();
+        let mut regs = Regs::default();
+        track_regs(&mut regs, arch, &tokens);
+        li(arch, reg, value, &regs, (1, 0..0).into())
+            .into_iter()
+            .map(|token| token.value)
+            .collect()
+    }
+
+    fn ops(bytes: &[u8]) -> Vec<Token> {
+        bytes.iter().map(|b| Token::Op(OpToken::Full(*b))).collect()
+    }
+
+    #[test]
+    fn li_shortest() {
+        let v2 = Architecture::Nna8v2;
+        // lil, lih, mov
+        assert_eq!(
+            expand(v2, &[], 1, ValueToken::Const(0x1a)),
+            ops(&[0x9a, 0xa1, 0xb4])
+        );
+        // r0 is zero so lih isn't needed
+        assert_eq!(
+            expand(v2, &[0xd0], 1, ValueToken::Const(0x05)),
+            ops(&[0x95, 0xb4])
+        );
+        // r1 = 0x1a, r0 = 0x1a
+        let loaded = [0x9a, 0xa1, 0xb4];
+        assert_eq!(
+            expand(v2, &loaded, 2, ValueToken::Const(0x1a)),
+            ops(&[0xb8])
+        );
+        assert_eq!(expand(v2, &loaded, 1, ValueToken::Const(0x1a)), ops(&[]));
+        assert_eq!(
+            expand(v2, &loaded, 1, ValueToken::Const(0x1d)),
+            ops(&[0xe6])
+        );
+        assert_eq!(
+            expand(v2, &loaded, 1, ValueToken::Const(0x16)),
+            ops(&[0xf7])
+        );
+        // only one nibble changes
+        assert_eq!(
+            expand(v2, &loaded, 0, ValueToken::Const(0x1d)),
+            ops(&[0x9d])
+        );
+        // nna8v1 can only increment by one
+        let v1_loaded = [0x1a, 0x21, 0x54];
+        assert_eq!(
+            expand(Architecture::Nna8v1, &v1_loaded, 1, ValueToken::Const(0x1b)),
+            ops(&[0x06])
+        );
+        assert_eq!(
+            expand(Architecture::Nna8v1, &v1_loaded, 1, ValueToken::Const(0x1c)),
+            ops(&[0x1c, 0x54])
+        );
+
+        // labels are always loaded using lil and lih
+        assert_eq!(
+            expand(
+                v2,
+                &[0xd0],
+                0,
+                ValueToken::LabelRef("a".into(), RefType::Full)
+            ),
+            vec![
+                Token::Op(OpToken::LabelRef(0x90, "a".into(), RefType::Low)),
+                Token::Op(OpToken::LabelRef(0xa0, "a".into(), RefType::High)),
+            ]
+        );
+        assert_eq!(
+            expand(
+                v2,
+                &[0xd0],
+                3,
+                ValueToken::LabelRef("a".into(), RefType::High)
+            ),
+            vec![
+                Token::Op(OpToken::LabelRef(0x90, "a".into(), RefType::High)),
+                Token::Op(OpToken::Full(0xbc)),
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_ops() {
+        assert_eq!(encode::<Nna8v1>("mov", 3, 0), Some(0x5C));
+        assert_eq!(encode::<Nna8v1>("jmp", 3, 0), Some(0x0D));
+        assert_eq!(encode::<Nna8v1>("lil", 0xA, 0), Some(0x1A));
+        assert_eq!(encode::<Nna8v2>("mdb", 0, 0), Some(0x03));
+        assert_eq!(encode::<Nna8v2>("clf", 0, 0), Some(0x34));
+        assert_eq!(encode::<Nna8v1>("mdb", 0, 0), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! "Did you mean" suggestions for misspelled names
+
+use libnna::Arch;
+
+/// Instructions that are expanded by the assembler
+pub const PSEUDO_OPS: &[&str] = &["li", "call", "ret"];
+
+/// The lowest encoding of every instruction of an architecture (one per mnemonic)
+pub fn instructions<I: Arch>() -> Vec<I> {
+    let mut ops: Vec<I> = Vec::new();
+    for byte in 0..=255 {
+        if let Some(op) = I::decode(byte) {
+            if !ops.iter().any(|other| other.name() == op.name()) {
+                ops.push(op);
+            }
+        }
+    }
+    ops
+}
+
+/// The mnemonics of every instruction of an architecture
+pub fn mnemonics<I: Arch>() -> Vec<&'static str> {
+    instructions::<I>().into_iter().map(I::name).collect()
+}
+
+/// The number of single character insertions, deletions, substitutions or swaps to turn one word into the other
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // rows of the optimal string alignment distance matrix
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut cur = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut prev, cur);
+    }
+    prev[b.len()]
+}
+
+/// How many characters two words have in common (regardless of their order)
+fn common(a: &str, b: &str) -> usize {
+    let mut b: Vec<char> = b.chars().collect();
+    a.chars()
+        .filter(|c| match b.iter().position(|other| other == c) {
+            Some(i) => {
+                b.swap_remove(i);
+                true
+            }
+            None => false,
+        })
+        .count()
+}
+
+/// The candidate closest to a word if it is close enough to be a typo (and no other is as close).
+/// Candidates at the same distance are ranked by the characters they share with the word (so swapped characters win).
+pub fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let word = word.to_lowercase();
+    let max = (word.chars().count() / 3).max(1);
+    let mut best: Option<((usize, usize), &str)> = None;
+    let mut tied = false;
+    for candidate in candidates {
+        let distance = distance(&word, candidate);
+        if distance > max {
+            continue;
+        }
+        // lower is closer
+        let rank = (distance, usize::MAX - common(&word, candidate));
+        match best {
+            Some((best_rank, best)) if rank == best_rank => tied |= best != candidate,
+            Some((best_rank, _)) if rank > best_rank => {}
+            _ => {
+                best = Some((rank, candidate));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|_| !tied).map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::instruction_sets::Nna8v2;
+
+    use super::{closest, distance, mnemonics};
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(distance("lil", "lil"), 0);
+        assert_eq!(distance("lli", "lil"), 1);
+        assert_eq!(distance("mvo", "mov"), 1);
+        assert_eq!(distance("include", "include_bytes"), 6);
+
+        let ops = mnemonics::<Nna8v2>();
+        assert_eq!(closest("mvo", ops.iter().copied()), Some("mov"));
+        assert_eq!(closest("LIH", ops.iter().copied()), Some("lih"));
+        assert_eq!(closest("xyzzy", ops.iter().copied()), None);
+        assert_eq!(closest("r4", ["r0", "r1", "r2", "r3"]), None);
+        assert_eq!(closest("sbu", ["add", "sub", "and"]), Some("sub"));
+    }
+}
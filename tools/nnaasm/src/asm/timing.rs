@@ -0,0 +1,172 @@
+//! Static cycle counts of blocks and routines
+
+use std::fmt::{Display, Write};
+
+use super::cfg::Cfg;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub best: u32,
+    /// None when the code contains a loop
+    pub worst: Option<u32>,
+}
+impl Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.worst {
+            Some(worst) => write!(f, "best {}, worst {} cycles", self.best, worst),
+            None => write!(f, "best {} cycles, worst unbounded (loop)", self.best),
+        }
+    }
+}
+
+/// The cycles it takes to execute a block once
+pub fn block_timing(cfg: &Cfg, block: usize) -> Timing {
+    let cycles = cfg.blocks[block].edges.iter().map(|edge| edge.cycles);
+    Timing {
+        best: cycles.clone().min().unwrap_or(0),
+        worst: cycles.max(),
+    }
+}
+
+/// The cycles it takes from the start of a routine until control leaves it.
+/// (by jumping to an unknown address or reaching the start of another routine)
+pub fn routine_timing(cfg: &Cfg, entry: usize) -> Timing {
+    let inside = |block: usize| block == entry || !cfg.blocks[block].is_routine();
+
+    // shortest path (dijkstra)
+    let mut dist = vec![None; cfg.blocks.len()];
+    let mut done = vec![false; cfg.blocks.len()];
+    dist[entry] = Some(0);
+    let mut best = None;
+    while let Some(block) = (0..cfg.blocks.len())
+        .filter(|b| !done[*b] && dist[*b].is_some())
+        .min_by_key(|b| dist[*b])
+    {
+        done[block] = true;
+        let start = dist[block].unwrap();
+        for edge in cfg.blocks[block].edges.iter() {
+            let cycles = start + edge.cycles;
+            match edge.target.filter(|t| inside(*t)) {
+                Some(target) => {
+                    if dist[target].is_none_or(|d| cycles < d) {
+                        dist[target] = Some(cycles);
+                    }
+                }
+                None => best = Some(best.map_or(cycles, |b: u32| b.min(cycles))),
+            }
+        }
+    }
+
+    // longest path. Any block that can reach a block on the current path is part of a loop
+    fn longest(
+        cfg: &Cfg,
+        block: usize,
+        inside: &dyn Fn(usize) -> bool,
+        on_path: &mut [bool],
+        memo: &mut [Option<Option<u32>>],
+    ) -> Option<u32> {
+        if let Some(worst) = memo[block] {
+            return worst;
+        }
+        on_path[block] = true;
+        let mut worst = Some(0);
+        for edge in cfg.blocks[block].edges.iter() {
+            let rest = match edge.target.filter(|t| inside(*t)) {
+                Some(target) if on_path[target] => None,
+                Some(target) => longest(cfg, target, inside, on_path, memo),
+                None => Some(0),
+            };
+            worst = worst.zip(rest).map(|(w, r)| w.max(edge.cycles + r));
+        }
+        on_path[block] = false;
+        memo[block] = Some(worst);
+        worst
+    }
+
+    let len = cfg.blocks.len();
+    Timing {
+        best: best.unwrap_or(0),
+        worst: longest(
+            cfg,
+            entry,
+            &inside,
+            &mut vec![false; len],
+            &mut vec![None; len],
+        ),
+    }
+}
+
+/// Lists the timing of every block grouped by the routine it is in
+pub fn report(cfg: &Cfg) -> String {
+    let mut out = String::new();
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let (line, _) = block.ops[0].location.clone().into();
+        if let Some(routine) = block.labels.iter().find(|label| label.is_global()) {
+            let _ = writeln!(
+                out,
+                "{} (line {}): {}",
+                routine.display_name(),
+                line + 1,
+                routine_timing(cfg, i)
+            );
+        }
+        let labels: Vec<&str> = block
+            .labels
+            .iter()
+            .filter(|label| !label.is_global())
+            .map(|label| label.display_name())
+            .collect();
+        let _ = writeln!(
+            out,
+            "  {} {}(line {}): {}",
+            block.start(),
+            labels.iter().map(|l| format!("{} ", l)).collect::<String>(),
+            line + 1,
+            block_timing(cfg, i)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use super::{block_timing, routine_timing, Timing};
+    use crate::asm::{assemble, cfg::Cfg, Options};
+
+    #[test]
+    fn timing() {
+        let code = r#".org 0x00
+start:
+lil 0x1
+mrd r1 r0
+.loop:
+dec r1 0x1
+bra &.loop.low
+clf
+jmp r0
+
+other:
+eq r0 r1
+bra &.skip.low
+nop
+nop
+.skip:
+clf
+jmp r0
+"#;
+        let program = assemble(code, &Options::new(Architecture::Nna8v2)).unwrap();
+        let cfg = Cfg::new(&program);
+        let timing = |best, worst| Timing { best, worst };
+
+        assert_eq!(cfg.blocks.len(), 6);
+        assert_eq!(block_timing(&cfg, 0), timing(3, Some(3)));
+        assert_eq!(block_timing(&cfg, 1), timing(2, Some(3)));
+        assert_eq!(block_timing(&cfg, 2), timing(3, Some(3)));
+        assert_eq!(routine_timing(&cfg, 0), timing(8, None));
+
+        assert!(cfg.blocks[3].is_routine());
+        assert_eq!(routine_timing(&cfg, 3), timing(6, Some(7)));
+    }
+}
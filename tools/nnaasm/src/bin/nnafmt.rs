@@ -0,0 +1,108 @@
+use clap::Parser;
+use libnna::Architecture;
+use nnaasm::{asm::fmt, Options};
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    process,
+};
+
+/// Formats nnaasm sources
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Files to format in place (formats stdin to stdout when there are none)
+    files: Vec<PathBuf>,
+
+    /// Only list the files that aren't formatted and fail if there are any
+    #[arg(long)]
+    check: bool,
+
+    /// The instruction set used to check that the output doesn't change (takes less precedence than a .arch directive)
+    #[arg(short = 'a', long, default_value = "nna8v1")]
+    arch: Architecture,
+
+    /// Directory to search for included files
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+}
+
+/// Formats a source and makes sure it still assembles to the same image
+fn format(code: &str, path: Option<PathBuf>, cli: &Cli) -> Result<String, String> {
+    let formatted = fmt::format(code);
+    let options = Options {
+        path,
+        include_paths: cli.include_dirs.clone(),
+        ..Options::new(cli.arch)
+    };
+    match fmt::same_output(code, &formatted, &options) {
+        Ok(true) => Ok(formatted),
+        Ok(false) => {
+            Err("formatting would change the assembled output (please report this)".into())
+        }
+        Err((line, message)) => Err(format!(
+            "can't be formatted because it doesn't assemble (line {}: {})",
+            line + 1,
+            message
+        )),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if cli.files.is_empty() {
+        let mut code = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut code) {
+            eprintln!("Failed to read stdin\n{}", err);
+            process::exit(1);
+        }
+        match format(&code, None, &cli) {
+            Ok(formatted) if cli.check && formatted != code => {
+                println!("stdin");
+                process::exit(1);
+            }
+            Ok(formatted) if !cli.check => print!("{}", formatted),
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("stdin: {}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut failed = false;
+    for path in cli.files.iter() {
+        let code = match fs::read_to_string(path) {
+            Ok(code) => code,
+            Err(err) => {
+                eprintln!("Failed to read '{}'\n{}", path.display(), err);
+                failed = true;
+                continue;
+            }
+        };
+        let formatted = match format(&code, Some(path.clone()), &cli) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == code {
+            continue;
+        }
+        if cli.check {
+            println!("{}", path.display());
+            failed = true;
+        } else if let Err(err) = fs::write(path, formatted) {
+            eprintln!("Failed to write '{}'\n{}", path.display(), err);
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
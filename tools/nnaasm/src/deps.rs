@@ -0,0 +1,92 @@
+//! Make style dependency files (`out.bin: main.asm data.bin`)
+
+use std::path::{Path, PathBuf};
+
+/// Escapes the characters Make treats specially (spaces, variables and comments)
+fn escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+/// A rule saying the targets depend on the files
+pub fn write(targets: &[PathBuf], deps: &[PathBuf]) -> String {
+    let targets: Vec<String> = targets.iter().map(|path| escape(path)).collect();
+    let mut out = format!("{}:", targets.join(" "));
+    for dep in deps {
+        out.push_str(" \\\n  ");
+        out.push_str(&escape(dep));
+    }
+    out.push('\n');
+    out
+}
+
+/// The dependencies of the first rule in a dependency file
+pub fn read(content: &str) -> Vec<PathBuf> {
+    let joined = content.replace("\\\n", " ");
+    let rule = joined.lines().next().unwrap_or_default();
+    // the first unescaped colon followed by whitespace ends the targets (windows paths contain colons)
+    let mut deps = rule;
+    let mut escaped = false;
+    for (i, c) in rule.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            ':' if !escaped
+                && (rule[i + 1..].is_empty() || rule[i + 1..].starts_with([' ', '\t'])) =>
+            {
+                deps = &rule[i + 1..];
+                break;
+            }
+            _ => escaped = false,
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut chars = deps.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ' | '#')) => {
+                cur.extend(chars.next());
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                cur.extend(chars.next());
+            }
+            c if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    out.push(PathBuf::from(std::mem::take(&mut cur)));
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        out.push(PathBuf::from(cur));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{read, write};
+
+    #[test]
+    fn roundtrip() {
+        let deps = vec![
+            PathBuf::from("main.asm"),
+            PathBuf::from("my data.bin"),
+            PathBuf::from("$HOME/#1.bin"),
+        ];
+        let file = write(&[PathBuf::from("bin/out.hex")], &deps);
+        assert_eq!(
+            file,
+            "bin/out.hex: \\\n  main.asm \\\n  my\\ data.bin \\\n  $$HOME/\\#1.bin\n"
+        );
+        assert_eq!(read(&file), deps);
+        assert_eq!(read("c:\\out.bin: a.asm\n"), [PathBuf::from("a.asm")]);
+        assert!(read("out.bin:\n").is_empty());
+    }
+}
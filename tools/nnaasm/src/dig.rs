@@ -0,0 +1,360 @@
+//! Support for the file formats of the [Digital](https://github.com/hneemann/Digital) simulator
+
+use std::{fmt::Display, ops::Range};
+
+/// Element names of memory components that can hold a program
+const ROM_ELEMENTS: &[&str] = &["ROM", "EEPROM", "ROMDualPort", "EEPROMDualPort"];
+
+/// Digital starts a new line once a line is longer than this
+const MAX_LINE_LEN: usize = 60;
+
+/// Runs of at least this many equal values are written as `count*value`
+const MIN_RUN_LEN: usize = 3;
+
+/// Writes data in the format Digital uses for memory contents. (a comma separated hex list like `c,10,4*0,2f`)
+pub fn write_data(data: &[u8]) -> String {
+    let len = data.len() - data.iter().rev().take_while(|b| **b == 0).count();
+    let data = &data[..len];
+
+    let mut out = String::new();
+    let mut line_len = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let value = data[i];
+        let run = data[i..].iter().take_while(|b| **b == value).count();
+        let item = if run >= MIN_RUN_LEN {
+            i += run;
+            format!("{}*{:x}", run, value)
+        } else {
+            i += 1;
+            format!("{:x}", value)
+        };
+
+        if !out.is_empty() {
+            out.push(',');
+            line_len += 1;
+        }
+        out.push_str(&item);
+        line_len += item.len();
+        if line_len > MAX_LINE_LEN && i < data.len() {
+            out.push('\n');
+            line_len = 0;
+        }
+    }
+    out
+}
+
+/// Reads data written in the format Digital uses for memory contents. (see [write_data])
+pub fn read_data(data: &str) -> Result<Vec<u8>, DigError> {
+    let mut out = Vec::new();
+    for item in data.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let (count, value) = match item.split_once('*') {
+            Some((count, value)) => (
+                count
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| DigError::InvalidData(item.to_string()))?,
+                value.trim(),
+            ),
+            None => (1, item),
+        };
+        let value =
+            u8::from_str_radix(value, 16).map_err(|_| DigError::InvalidData(item.to_string()))?;
+        out.resize(out.len() + count, value);
+    }
+    Ok(out)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DigError {
+    NoRom,
+    RomNotFound(String, Vec<String>),
+    AmbiguousRom(Vec<String>),
+    TooBig { size: usize, capacity: usize },
+    Malformed(&'static str),
+    InvalidData(String),
+}
+impl Display for DigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoRom => f.write_str("The circuit doesn't contain a ROM or EEPROM"),
+            Self::RomNotFound(label, labels) => write!(
+                f,
+                "No ROM or EEPROM with label '{}' found. Available labels: {}",
+                label,
+                labels.join(", ")
+            ),
+            Self::AmbiguousRom(labels) => write!(
+                f,
+                "The circuit contains multiple ROMs or EEPROMs. Select one using --rom-label. Available labels: {}",
+                labels.join(", ")
+            ),
+            Self::TooBig { size, capacity } => write!(
+                f,
+                "The program ({} bytes) doesn't fit in the ROM ({} bytes)",
+                size, capacity
+            ),
+            Self::Malformed(what) => write!(f, "Malformed .dig file: {}", what),
+            Self::InvalidData(item) => write!(f, "Invalid memory data '{}'", item),
+        }
+    }
+}
+
+/// A memory element found in a circuit
+struct RomElement {
+    label: Option<String>,
+    addr_bits: Option<u32>,
+    /// The text between `<data>` and `</data>`
+    data: Option<Range<usize>>,
+    /// Position of `</elementAttributes>` and the indentation of the line it is on
+    attributes_end: (usize, Range<usize>),
+}
+
+fn find_from(xml: &str, start: usize, pattern: &str) -> Option<usize> {
+    xml[start..].find(pattern).map(|i| i + start)
+}
+
+/// The text between `<tag>` and `</tag>` starting the search at `start`
+fn tag_content(xml: &str, start: usize, end: usize, tag: &str) -> Option<Range<usize>> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let content_start = find_from(&xml[..end], start, &open)? + open.len();
+    let content_end = find_from(&xml[..end], content_start, &close)?;
+    Some(content_start..content_end)
+}
+
+fn unescape(str: &str) -> String {
+    str.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn find_roms(xml: &str) -> Result<Vec<RomElement>, DigError> {
+    let mut roms = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = find_from(xml, pos, "<visualElement>") {
+        let end = find_from(xml, start, "</visualElement>")
+            .ok_or(DigError::Malformed("unclosed <visualElement>"))?;
+        pos = end;
+
+        let name = tag_content(xml, start, end, "elementName")
+            .ok_or(DigError::Malformed("<visualElement> without <elementName>"))?;
+        if !ROM_ELEMENTS.contains(&&xml[name]) {
+            continue;
+        }
+        let attributes = find_from(&xml[..end], start, "<elementAttributes>")
+            .ok_or(DigError::Malformed("ROM without <elementAttributes>"))?;
+        let attributes_end = find_from(&xml[..end], attributes, "</elementAttributes>")
+            .ok_or(DigError::Malformed("unclosed <elementAttributes>"))?;
+        let line_start = xml[..attributes_end].rfind('\n').map_or(0, |i| i + 1);
+
+        let mut rom = RomElement {
+            label: None,
+            addr_bits: None,
+            data: None,
+            attributes_end: (attributes_end, line_start..attributes_end),
+        };
+        let mut entry_pos = attributes;
+        while let Some(entry) = tag_content(xml, entry_pos, attributes_end, "entry") {
+            entry_pos = entry.end;
+            let Some(key) = tag_content(xml, entry.start, entry.end, "string") else {
+                continue;
+            };
+            match &xml[key.clone()] {
+                "Label" => {
+                    rom.label = tag_content(xml, key.end, entry.end, "string")
+                        .map(|label| unescape(&xml[label]));
+                }
+                "AddrBits" => {
+                    rom.addr_bits = tag_content(xml, key.end, entry.end, "int")
+                        .and_then(|bits| xml[bits].trim().parse().ok());
+                }
+                "Data" => rom.data = tag_content(xml, key.end, entry.end, "data"),
+                _ => {}
+            }
+        }
+        roms.push(rom);
+    }
+    Ok(roms)
+}
+
+/// Finds the ROM or EEPROM with the given label. (or the only one if no label is given)
+fn select_rom<'a>(roms: &'a [RomElement], label: Option<&str>) -> Result<&'a RomElement, DigError> {
+    let labels = || {
+        roms.iter()
+            .map(|r| r.label.clone().unwrap_or_else(|| "<no label>".to_string()))
+            .collect::<Vec<_>>()
+    };
+    match label {
+        Some(label) => roms
+            .iter()
+            .find(|r| r.label.as_deref() == Some(label))
+            .ok_or_else(|| DigError::RomNotFound(label.to_string(), labels())),
+        None => match roms {
+            [] => Err(DigError::NoRom),
+            [rom] => Ok(rom),
+            _ => Err(DigError::AmbiguousRom(labels())),
+        },
+    }
+}
+
+/// Reads the contents of a ROM or EEPROM in a Digital circuit.
+pub fn read_rom(xml: &str, label: Option<&str>) -> Result<Vec<u8>, DigError> {
+    let roms = find_roms(xml)?;
+    let rom = select_rom(&roms, label)?;
+    match &rom.data {
+        Some(data) => read_data(&xml[data.clone()]),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Replaces the contents of a ROM or EEPROM in a Digital circuit. Everything else is left untouched.
+///
+/// If no label is given the circuit has to contain exactly one ROM or EEPROM.
+pub fn patch_rom(xml: &str, label: Option<&str>, data: &[u8]) -> Result<String, DigError> {
+    let roms = find_roms(xml)?;
+    let rom = select_rom(&roms, label)?;
+
+    let size = data.len() - data.iter().rev().take_while(|b| **b == 0).count();
+    if let Some(bits) = rom.addr_bits {
+        let capacity = 1usize.checked_shl(bits).unwrap_or(usize::MAX);
+        if size > capacity {
+            return Err(DigError::TooBig { size, capacity });
+        }
+    }
+
+    let data = write_data(data);
+    let mut out = String::with_capacity(xml.len() + data.len());
+    match &rom.data {
+        Some(range) => {
+            out.push_str(&xml[..range.start]);
+            out.push_str(&data);
+            out.push_str(&xml[range.end..]);
+        }
+        None => {
+            // Digital doesn't write a Data entry for empty memory so add one
+            let (end, indent) = &rom.attributes_end;
+            let indent = &xml[indent.clone()];
+            out.push_str(&xml[..*end]);
+            out.push_str(&format!(
+                "  <entry>\n{indent}    <string>Data</string>\n{indent}    <data>{data}</data>\n{indent}  </entry>\n{indent}"
+            ));
+            out.push_str(&xml[*end..]);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{patch_rom, read_data, read_rom, write_data, DigError};
+
+    const CIRCUIT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<circuit>
+  <visualElement>
+    <elementName>EEPROM</elementName>
+    <elementAttributes>
+      <entry>
+        <string>AddrBits</string>
+        <int>4</int>
+      </entry>
+      <entry>
+        <string>Label</string>
+        <string>ROM</string>
+      </entry>
+      <entry>
+        <string>Data</string>
+        <data>1,2,3</data>
+      </entry>
+    </elementAttributes>
+    <pos x="1300" y="-620"/>
+  </visualElement>
+  <visualElement>
+    <elementName>ROM</elementName>
+    <elementAttributes>
+      <entry>
+        <string>Label</string>
+        <string>font &amp; data</string>
+      </entry>
+    </elementAttributes>
+    <pos x="0" y="0"/>
+  </visualElement>
+</circuit>
+"#;
+
+    #[test]
+    fn data_format() {
+        assert_eq!(
+            write_data(&[0x0c, 0x10, 0, 0, 0, 0, 0x2f, 6, 6, 0, 0]),
+            "c,10,4*0,2f,6,6"
+        );
+        assert_eq!(write_data(&[0; 16]), "");
+
+        let long: Vec<u8> = (0x10..0x40).collect();
+        let written = write_data(&long);
+        let first_line = written.lines().next().unwrap();
+        assert_eq!(first_line.len(), 62);
+        assert!(written.lines().nth(1).unwrap().starts_with(",25"));
+    }
+
+    #[test]
+    fn read() {
+        assert_eq!(
+            read_data("c,10,4*0,2f\n,6, 6"),
+            Ok(vec![0x0c, 0x10, 0, 0, 0, 0, 0x2f, 6, 6])
+        );
+        assert_eq!(read_data(""), Ok(vec![]));
+        assert_eq!(
+            read_data("1,1ff"),
+            Err(DigError::InvalidData("1ff".to_string()))
+        );
+
+        let data: Vec<u8> = (0..=0xFF).chain([0xAA; 40]).chain([0x01]).collect();
+        assert_eq!(read_data(&write_data(&data)), Ok(data));
+
+        assert_eq!(read_rom(CIRCUIT, Some("ROM")), Ok(vec![1, 2, 3]));
+        assert_eq!(read_rom(CIRCUIT, Some("font & data")), Ok(vec![]));
+    }
+
+    #[test]
+    fn patch() {
+        let patched = patch_rom(CIRCUIT, Some("ROM"), &[0xAB, 0, 0, 0, 1]).unwrap();
+        assert_eq!(
+            patched,
+            CIRCUIT.replace("<data>1,2,3</data>", "<data>ab,3*0,1</data>")
+        );
+
+        let patched = patch_rom(CIRCUIT, Some("font & data"), &[0x01]).unwrap();
+        assert_eq!(
+            patched,
+            CIRCUIT.replace(
+                "font &amp; data</string>\n      </entry>\n",
+                "font &amp; data</string>\n      </entry>\n      <entry>\n        <string>Data</string>\n        <data>1</data>\n      </entry>\n"
+            )
+        );
+    }
+
+    #[test]
+    fn patch_errors() {
+        assert_eq!(
+            patch_rom(CIRCUIT, None, &[]),
+            Err(DigError::AmbiguousRom(vec![
+                "ROM".to_string(),
+                "font & data".to_string()
+            ]))
+        );
+        assert!(matches!(
+            patch_rom(CIRCUIT, Some("RAM"), &[]),
+            Err(DigError::RomNotFound(_, _))
+        ));
+        assert_eq!(
+            patch_rom(CIRCUIT, Some("ROM"), &[1; 17]),
+            Err(DigError::TooBig {
+                size: 17,
+                capacity: 16
+            })
+        );
+    }
+}
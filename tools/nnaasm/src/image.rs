@@ -0,0 +1,164 @@
+//! Reading and writing program images
+
+use std::{ffi::OsStr, fs, path::Path};
+
+use crate::{asm::Bank, dig};
+
+#[inline]
+fn u4_to_hex(val: u8) -> char {
+    let lower = val & 0x0F;
+    if lower > 9 {
+        (lower + 55) as char
+    } else {
+        (lower + 48) as char
+    }
+}
+
+#[inline]
+fn write_hexline(str: &mut String, byte: u8, repeat_count: usize) {
+    if repeat_count > 1 {
+        str.push_str(&repeat_count.to_string());
+        str.push('*');
+    }
+    str.push(u4_to_hex(byte >> 4));
+    str.push(u4_to_hex(byte));
+    str.push('\n');
+}
+
+pub fn write_hex(input: Vec<Bank>) -> Vec<u8> {
+    let mut output = "v2.0 raw\n".to_string();
+    let mut prev_byte = input[0][0];
+    let mut repeat_count = 0;
+    for byte in input.iter().flat_map(|b| b.iter()) {
+        if *byte == prev_byte {
+            repeat_count += 1;
+            continue;
+        }
+        write_hexline(&mut output, prev_byte, repeat_count);
+        prev_byte = *byte;
+        repeat_count = 1;
+    }
+    write_hexline(&mut output, prev_byte, repeat_count);
+    output.into_bytes()
+}
+/// Reads a file written by write_hex
+pub fn read_hex(input: &str) -> Result<Vec<u8>, String> {
+    let mut lines = input.lines();
+    if lines.next().map(str::trim) != Some("v2.0 raw") {
+        return Err("Missing 'v2.0 raw' header".to_string());
+    }
+    let mut output = Vec::new();
+    for line in lines {
+        let line = line.split('#').next().unwrap_or_default();
+        for value in line.split_whitespace() {
+            let (count, byte) = match value.split_once('*') {
+                Some((count, byte)) => (
+                    count
+                        .parse()
+                        .map_err(|_| format!("Invalid repeat count '{}'", count))?,
+                    byte,
+                ),
+                None => (1, value),
+            };
+            let byte =
+                u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte '{}'", byte))?;
+            output.resize(output.len() + count, byte);
+        }
+    }
+    Ok(output)
+}
+
+/// Splits an image into banks. (the last bank is padded with zeros)
+pub fn read_bin(input: &[u8]) -> Vec<Bank> {
+    input
+        .chunks(256)
+        .map(|chunk| {
+            let mut bank = [0; 256];
+            bank[..chunk.len()].copy_from_slice(chunk);
+            bank
+        })
+        .collect()
+}
+
+/// Reads an image from a .hex, Digital data list (.txt), Digital circuit (.dig) or binary file.
+/// The label selects the ROM of a circuit. (only needed when it contains multiple)
+pub fn read_base(path: &str, rom_label: Option<&str>) -> Result<Vec<Bank>, String> {
+    let read_text = || fs::read_to_string(path).map_err(|err| err.to_string());
+    let image = match Path::new(path).extension().and_then(OsStr::to_str) {
+        Some("hex") => read_hex(&read_text()?)?,
+        Some("txt") => dig::read_data(&read_text()?).map_err(|err| err.to_string())?,
+        Some("dig") => dig::read_rom(&read_text()?, rom_label).map_err(|err| err.to_string())?,
+        _ => fs::read(path).map_err(|err| err.to_string())?,
+    };
+    Ok(read_bin(&image))
+}
+
+pub fn write_bin(input: Vec<Bank>) -> Vec<u8> {
+    input.into_iter().flat_map(|b| b.into_iter()).collect()
+}
+
+pub fn write_digital(input: Vec<Bank>) -> Vec<u8> {
+    let mut output = dig::write_data(&write_bin(input));
+    output.push('\n');
+    output.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{read_base, read_bin, read_hex, write_digital, write_hex};
+    use crate::dig;
+
+    #[test]
+    fn hex_roundtrip() {
+        let mut bank = [0; 256];
+        bank[..4].copy_from_slice(&[0x12, 0x12, 0xAB, 0x00]);
+        bank[0x80] = 0xFF;
+        let hex = String::from_utf8(write_hex(vec![bank, bank])).unwrap();
+        assert_eq!(read_bin(&read_hex(&hex).unwrap()), vec![bank, bank]);
+    }
+
+    #[test]
+    fn read_hex_errors() {
+        assert_eq!(
+            read_hex("v2.0 raw\n3*1f 0a # comment\n"),
+            Ok(vec![0x1F, 0x1F, 0x1F, 0x0A])
+        );
+        assert!(read_hex("1f\n").is_err());
+        assert!(read_hex("v2.0 raw\nx*1f\n").is_err());
+        assert!(read_hex("v2.0 raw\n100\n").is_err());
+    }
+
+    #[test]
+    fn digital_base() {
+        let mut bank = [0; 256];
+        bank[..3].copy_from_slice(&[0x12, 0x00, 0xAB]);
+        bank[0xFF] = 0x01;
+        let dir = std::env::temp_dir().join(format!("nnaasm-base-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let list = dir.join("image.txt");
+        fs::write(&list, write_digital(vec![bank, bank])).unwrap();
+        assert_eq!(
+            read_base(list.to_str().unwrap(), None),
+            Ok(vec![bank, bank])
+        );
+
+        let circuit = dir.join("cpu.dig");
+        let xml = "<circuit>\n  <visualElement>\n    <elementName>ROM</elementName>\n    <elementAttributes>\n    </elementAttributes>\n  </visualElement>\n</circuit>\n";
+        fs::write(&circuit, dig::patch_rom(xml, None, &bank).unwrap()).unwrap();
+        assert_eq!(read_base(circuit.to_str().unwrap(), None), Ok(vec![bank]));
+        assert!(read_base(circuit.to_str().unwrap(), Some("RAM")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bin_banks() {
+        let banks = read_bin(&[1; 300]);
+        assert_eq!(banks.len(), 2);
+        assert_eq!(banks[1][43], 1);
+        assert_eq!(banks[1][44], 0);
+    }
+}
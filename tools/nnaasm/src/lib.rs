@@ -0,0 +1,27 @@
+//! Assembler for the nna processor architectures
+//!
+//! ```no_run
+//! use libnna::Architecture;
+//! use nnaasm::{assemble, IntoAsmError, Options};
+//!
+//! let code = ".org 0x00\nbrk\n";
+//! match assemble(code, &Options::new(Architecture::Nna8v2)) {
+//!     Ok(program) => println!("{:02x?}", &program.banks[0][..1]),
+//!     Err(err) => eprintln!("{}", err.into_asm_error(code, "main.asm".into()).render()),
+//! }
+//! ```
+
+pub mod asm;
+pub mod deps;
+pub mod dig;
+pub mod image;
+pub mod output;
+pub mod project;
+pub mod size;
+pub mod verilog;
+pub mod watch;
+
+pub use asm::{
+    assemble, codegen::Symbol, files, AsmError, Bank, Diagnostic, Error, IntoAsmError, Located,
+    Location, Options, Program, Severity,
+};
@@ -1,28 +1,43 @@
-#![allow(dead_code)]
-use asm::Bank;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use libnna::Architecture;
+use nnaasm::{
+    asm, deps, dig,
+    image::{read_base, write_bin},
+    output::{self, OutputFormat},
+    project::{self, Target},
+    size,
+    watch::Watcher,
+    IntoAsmError, Options, Program, Severity,
+};
 use std::{
     ffi::OsStr,
     fs,
     io::{self, Read},
-    path::Path,
+    path::{Path, PathBuf},
     process,
+    rc::Rc,
 };
 use stderrlog::LogLevelNum;
-mod asm;
 
-#[derive(ValueEnum, Clone)]
-enum OutputFormat {
-    Auto,
-    Bin,
-    Hex,
+#[derive(ValueEnum, Clone, PartialEq)]
+enum SizeFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, PartialEq)]
+enum WarningOption {
+    /// Treat warnings as errors
+    Error,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
-#[command(propagate_version = true)]
+#[command(propagate_version = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file or - to read from stdin
     #[arg(default_value = "-")]
     input: String,
@@ -39,6 +54,10 @@ struct Cli {
     #[arg(short = 'z', long)]
     size: bool,
 
+    /// The format of the size report (implies --size)
+    #[arg(long, value_name = "FORMAT")]
+    size_format: Option<SizeFormat>,
+
     /// The minimum log level
     #[arg(long, default_value = "0")]
     log_level: usize,
@@ -46,6 +65,96 @@ struct Cli {
     /// The format of the output file
     #[arg(short = 'f', long, default_value = "auto")]
     format: OutputFormat,
+
+    /// Write the program into the ROM of a Digital circuit (.dig) instead of the output file
+    #[arg(long, value_name = "CIRCUIT")]
+    patch_dig: Option<String>,
+
+    /// Label of the ROM or EEPROM to patch or read the base image from (only needed when the circuit contains multiple)
+    #[arg(long, value_name = "LABEL")]
+    rom_label: Option<String>,
+
+    /// Shorten instruction sequences that can be done with fewer instructions and print what was changed
+    #[arg(short = 'O', long)]
+    optimize: bool,
+
+    /// Don't check the program for likely mistakes
+    #[arg(long)]
+    no_lint: bool,
+
+    /// Warning options (-Werror fails the build when there are warnings)
+    #[arg(short = 'W', value_name = "OPTION")]
+    warning_options: Vec<WarningOption>,
+
+    /// Prints best and worst case cycle counts of every block and labelled routine
+    #[arg(long)]
+    timing: bool,
+
+    /// Write the control flow graph to a file (Graphviz DOT or JSON when the file ends in .json)
+    #[arg(long, value_name = "FILE")]
+    cfg: Option<String>,
+
+    /// Write a listing of the addresses, bytes and disassembly of every instruction next to its source line
+    #[arg(long, value_name = "FILE")]
+    listing: Option<String>,
+
+    /// Assemble on top of an existing image (.hex, .bin, Digital data .txt or the ROM of a .dig circuit) instead of an empty one
+    #[arg(long, value_name = "IMAGE")]
+    base: Option<String>,
+
+    /// Define a symbol that can be used in expressions
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+    defines: Vec<(Box<str>, u64)>,
+
+    /// Directory to search for included files
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+
+    /// Write a Make style list of the files the output depends on (the input and every included file)
+    #[arg(long, value_name = "FILE")]
+    depfile: Option<PathBuf>,
+
+    /// Write the dependency list next to the output with the extension .d (like -MD of gcc)
+    #[arg(long = "MD")]
+    md: bool,
+
+    /// Reassemble whenever the input or an included file changes (the output is only written when it changed)
+    #[arg(short = 'w', long)]
+    watch: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble every program of a project described by an nna.toml manifest
+    Build {
+        /// The manifest of the project
+        #[arg(long, default_value = project::MANIFEST)]
+        manifest: PathBuf,
+
+        /// Build programs even if their outputs are up to date
+        #[arg(long)]
+        force: bool,
+
+        /// Warning options (-Werror fails a program when it has warnings)
+        #[arg(short = 'W', value_name = "OPTION")]
+        warning_options: Vec<WarningOption>,
+    },
+}
+
+/// Parses `NAME=VALUE` where the value is decimal or hex (`0x` prefix)
+fn parse_define(define: &str) -> Result<(Box<str>, u64), String> {
+    let (name, value) = define
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=VALUE but found '{}'", define))?;
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("Invalid value '{}'", value))?;
+    if let Some(kind) = asm::reserved_name(name) {
+        return Err(format!("'{}' is {} and can't be defined", name, kind));
+    }
+    Ok((name.into(), value))
 }
 
 fn get_input_data(path: &str) -> io::Result<(Box<str>, String)> {
@@ -56,11 +165,10 @@ fn get_input_data(path: &str) -> io::Result<(Box<str>, String)> {
         stdin.read_to_string(&mut str)?;
         Ok(("stdin".into(), str))
     } else {
-        let can_path = std::fs::canonicalize(&path)?;
+        let can_path = std::fs::canonicalize(path)?;
         let filename = can_path
             .file_name()
-            .map(|f| f.to_str())
-            .flatten()
+            .and_then(|f| f.to_str())
             .unwrap_or("unknown_file")
             .into();
         let str = fs::read_to_string(can_path)?;
@@ -75,49 +183,13 @@ macro_rules! die {
     };
 }
 
-#[inline]
-fn u4_to_hex(val: u8) -> char {
-    let lower = val & 0x0F;
-    if lower > 9 {
-        (lower + 55) as char
-    } else {
-        (lower + 48) as char
-    }
-}
-
-#[inline]
-fn write_hexline(str: &mut String, byte: u8, repeat_count: usize) {
-    if repeat_count > 1 {
-        str.push_str(&repeat_count.to_string());
-        str.push('*');
-    }
-    str.push(u4_to_hex(byte >> 4));
-    str.push(u4_to_hex(byte));
-    str.push('\n');
-}
-
-fn write_hex(input: Vec<Bank>) -> Vec<u8> {
-    let mut output = "v2.0 raw\n".to_string();
-    let mut prev_byte = input[0][0];
-    let mut repeat_count = 0;
-    for byte in input.iter().flat_map(|b| b.iter()) {
-        if *byte == prev_byte {
-            repeat_count += 1;
-            continue;
-        }
-        write_hexline(&mut output, prev_byte, repeat_count);
-        prev_byte = *byte;
-        repeat_count = 1;
-    }
-    write_hexline(&mut output, prev_byte, repeat_count);
-    output.into_bytes()
-}
-fn write_bin(input: Vec<Bank>) -> Vec<u8> {
-    input.into_iter().flat_map(|b| b.into_iter()).collect()
-}
-
 fn main() {
-    let cli = Cli::parse();
+    // clap only supports single letter short flags
+    let args = std::env::args_os().map(|arg| match arg.to_str() {
+        Some("-MD") => "--MD".into(),
+        _ => arg,
+    });
+    let cli = Cli::parse_from(args);
     stderrlog::new()
         .verbosity(LogLevelNum::from(cli.log_level))
         .module(module_path!())
@@ -125,42 +197,282 @@ fn main() {
         .init()
         .unwrap();
 
-    let output_file = Path::new(&cli.output);
+    if let Some(Command::Build {
+        manifest,
+        force,
+        warning_options,
+    }) = &cli.command
+    {
+        build(
+            manifest,
+            *force,
+            warning_options.contains(&WarningOption::Error),
+        );
+    }
+
+    let base = match &cli.base {
+        Some(path) => read_base(path, cli.rom_label.as_deref()).unwrap_or_else(|err| {
+            die!("Failed to read base image '{}'\n{}", path, err);
+        }),
+        None => Vec::new(),
+    };
+    let options = Options {
+        defines: cli.defines.iter().cloned().collect(),
+        path: (cli.input != "-").then(|| PathBuf::from(&cli.input)),
+        include_paths: cli.include_dirs.clone(),
+        base,
+        optimize: cli.optimize,
+        lint: !cli.no_lint,
+        ..Options::new(cli.arch)
+    };
+
+    if cli.watch {
+        watch(&cli, &options);
+    }
 
     let (filename, input_data) = get_input_data(&cli.input).unwrap_or_else(|err: io::Error| {
         die!("Failed to read '{}'\n{}", cli.input, err);
     });
+    let Some(program) = assemble(&cli, &input_data, filename.into(), &options) else {
+        process::exit(1);
+    };
+    write_outputs(&cli, program, &input_data, false);
+}
 
-    let output = match asm::assemble(filename.into(), &input_data, cli.arch) {
+/// Assembles the input and prints the error or diagnostics
+fn assemble(cli: &Cli, input_data: &str, filename: Rc<str>, options: &Options) -> Option<Program> {
+    let mut program = match asm::assemble(input_data, options) {
         Ok(out) => out,
         Err(err) => {
-            err.print();
-            process::exit(1);
+            err.into_asm_error(input_data, filename).print();
+            return None;
         }
     };
+    let warnings = program
+        .diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity() == Severity::Warning)
+        .count();
+    for diagnostic in program.diagnostics.drain(..) {
+        diagnostic
+            .into_asm_error(input_data, filename.clone())
+            .print();
+    }
+    if warnings > 0 && cli.warning_options.contains(&WarningOption::Error) {
+        eprintln!("{} warning(s) treated as errors (-Werror)", warnings);
+        return None;
+    }
+    Some(program)
+}
 
-    if cli.size {
-        for (i, bank) in asm::codegen::calc_mem_usage(&output, 256)
-            .iter()
-            .enumerate()
-        {
-            println!("bank {:#x} Using {}/{} bytes", i, bank.start, bank.end);
+/// Writes a file unless `only_changed` is set and it already has that content
+fn write_file(path: &Path, data: &[u8], only_changed: bool) {
+    if only_changed && fs::read(path).is_ok_and(|old| old == data) {
+        println!("'{}' is unchanged", path.display());
+        return;
+    }
+    fs::write(path, data).unwrap_or_else(|err| {
+        die!("Failed to write '{}'\n{}", path.display(), err);
+    });
+    if only_changed {
+        println!("Wrote '{}'", path.display());
+    }
+}
+
+/// Prints the reports and writes the output files
+fn write_outputs(cli: &Cli, program: Program, input_data: &str, watching: bool) {
+    if cli.size || cli.size_format.is_some() || watching {
+        let usage = asm::codegen::calc_mem_usage(&program.orgs, program.arch);
+        match cli.size_format {
+            Some(SizeFormat::Json) => println!("{}", size::json(&usage)),
+            _ => print!("{}", size::text(&usage)),
         }
     }
 
-    let output = match cli.format {
-        OutputFormat::Bin => write_bin(output),
-        OutputFormat::Hex => write_hex(output),
-        OutputFormat::Auto => {
-            if output_file.extension() == Some(OsStr::new("hex")) {
-                write_hex(output)
-            } else {
-                write_bin(output)
+    if cli.timing {
+        print!("{}", asm::timing::report(&asm::cfg::Cfg::new(&program)));
+    }
+
+    if let Some(path) = &cli.cfg {
+        let cfg = asm::cfg::Cfg::new(&program);
+        let graph = if Path::new(path).extension() == Some(OsStr::new("json")) {
+            cfg.to_json()
+        } else {
+            cfg.to_dot()
+        };
+        fs::write(path, graph).unwrap_or_else(|err| {
+            die!("Failed to write '{}'\n{}", path, err);
+        });
+    }
+
+    if let Some(path) = &cli.listing {
+        let listing = asm::listing::listing(&program, input_data);
+        fs::write(path, listing).unwrap_or_else(|err| {
+            die!("Failed to write '{}'\n{}", path, err);
+        });
+    }
+
+    if let Some(circuit) = &cli.patch_dig {
+        let xml = fs::read_to_string(circuit).unwrap_or_else(|err| {
+            die!("Failed to read '{}'\n{}", circuit, err);
+        });
+        let image = write_bin(program.banks);
+        let patched =
+            dig::patch_rom(&xml, cli.rom_label.as_deref(), &image).unwrap_or_else(|err| {
+                die!("Failed to patch '{}'\n{}", circuit, err);
+            });
+        write_file(Path::new(circuit), patched.as_bytes(), watching);
+        write_depfile(cli, &program.includes, Path::new(circuit), watching);
+        return;
+    }
+
+    let output_file = Path::new(&cli.output);
+    let output = output::write(&program, cli.format, output_file);
+    write_file(output_file, &output, watching);
+    write_depfile(cli, &program.includes, output_file, watching);
+}
+
+/// Writes the files the output depends on if --depfile or -MD are used
+fn write_depfile(cli: &Cli, includes: &[PathBuf], output_file: &Path, watching: bool) {
+    let path = match (&cli.depfile, cli.md) {
+        (Some(path), _) => path.clone(),
+        (None, true) => output_file.with_extension("d"),
+        (None, false) => return,
+    };
+    let inputs: Vec<PathBuf> = (cli.input != "-")
+        .then(|| PathBuf::from(&cli.input))
+        .into_iter()
+        .chain(includes.iter().cloned())
+        .collect();
+    let rule = deps::write(&[output_file.to_path_buf()], &inputs);
+    write_file(&path, rule.as_bytes(), watching);
+}
+
+/// Reassembles whenever the input or a file it includes changes
+fn watch(cli: &Cli, options: &Options) -> ! {
+    if cli.input == "-" {
+        die!("--watch needs an input file");
+    }
+    let mut watcher = Watcher::new().unwrap_or_else(|err| {
+        die!("Failed to watch for changes\n{}", err);
+    });
+    let mut files = vec![PathBuf::from(&cli.input)];
+    loop {
+        match get_input_data(&cli.input) {
+            Ok((filename, input_data)) => {
+                // the includes of the last successful build are kept watching after errors
+                if let Some(program) = assemble(cli, &input_data, filename.into(), options) {
+                    files.truncate(1);
+                    files.extend(program.includes.iter().cloned());
+                    write_outputs(cli, program, &input_data, true);
+                }
             }
+            Err(err) => eprintln!("Failed to read '{}'\n{}", cli.input, err),
+        }
+        println!("Watching {} file(s) for changes...", files.len());
+        watcher.wait(&files).unwrap_or_else(|err| {
+            die!("Failed to watch for changes\n{}", err);
+        });
+    }
+}
+
+enum Outcome {
+    Built(usize),
+    UpToDate,
+    Failed,
+}
+
+fn build_target(target: &Target, manifest: &Path, force: bool, werror: bool) -> Outcome {
+    if !force && target.up_to_date(manifest) {
+        return Outcome::UpToDate;
+    }
+    // so a failed build isn't seen as up to date
+    let _ = fs::remove_file(target.depfile());
+
+    let input = target.input.display().to_string();
+    let code = match fs::read_to_string(&target.input) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Failed to read '{}'\n{}", input, err);
+            return Outcome::Failed;
+        }
+    };
+    let filename: Rc<str> = input.as_str().into();
+    let mut program = match asm::assemble(&code, &target.options()) {
+        Ok(program) => program,
+        Err(err) => {
+            err.into_asm_error(&code, filename).print();
+            return Outcome::Failed;
         }
     };
+    let warnings = program
+        .diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity() == Severity::Warning)
+        .count();
+    for diagnostic in program.diagnostics.drain(..) {
+        diagnostic.into_asm_error(&code, filename.clone()).print();
+    }
+    if warnings > 0 && werror {
+        eprintln!("{} warning(s) treated as errors (-Werror)", warnings);
+        return Outcome::Failed;
+    }
+
+    for (path, format) in target.outputs.iter() {
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(path, output::write(&program, *format, path)));
+        if let Err(err) = written {
+            eprintln!("Failed to write '{}'\n{}", path.display(), err);
+            return Outcome::Failed;
+        }
+    }
+    let outputs: Vec<PathBuf> = target
+        .outputs
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect();
+    let inputs: Vec<PathBuf> = std::iter::once(target.input.clone())
+        .chain(program.includes.iter().cloned())
+        .collect();
+    if let Err(err) = fs::write(target.depfile(), deps::write(&outputs, &inputs)) {
+        eprintln!("Failed to write '{}'\n{}", target.depfile().display(), err);
+    }
 
-    fs::write(output_file, output).unwrap_or_else(|err| {
-        die!("Failed to write output file:\n{}", err);
+    let usage = asm::codegen::calc_mem_usage(&program.orgs, program.arch);
+    Outcome::Built(usage.iter().map(|bank| bank.used()).sum())
+}
+
+/// Builds every program of a project, prints a summary and exits
+fn build(manifest: &Path, force: bool, werror: bool) -> ! {
+    let targets = project::load(manifest).unwrap_or_else(|err| {
+        die!("{}", err);
     });
+    let (mut built, mut fresh, mut failed) = (0, 0, 0);
+    for target in targets.iter() {
+        match build_target(target, manifest, force, werror) {
+            Outcome::Built(used) => {
+                built += 1;
+                let outputs: Vec<String> = target
+                    .outputs
+                    .iter()
+                    .map(|(path, _)| path.display().to_string())
+                    .collect();
+                println!(
+                    "Built {} -> {} ({} bytes)",
+                    target.name,
+                    outputs.join(", "),
+                    used
+                );
+            }
+            Outcome::UpToDate => fresh += 1,
+            Outcome::Failed => {
+                failed += 1;
+                println!("Failed to build {}", target.name);
+            }
+        }
+    }
+    println!("{} built, {} up to date, {} failed", built, fresh, failed);
+    process::exit(if failed > 0 { 1 } else { 0 });
 }
@@ -0,0 +1,68 @@
+//! Writing a program in one of the output formats
+
+use std::{ffi::OsStr, path::Path};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    image::{write_bin, write_digital, write_hex},
+    verilog, Program,
+};
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    Auto,
+    Bin,
+    Hex,
+    /// Comma separated list used by the Digital simulator
+    Digital,
+    /// One byte per line for Verilog's $readmemh
+    Memh,
+    /// A synthesizable Verilog ROM module named after the output file
+    VerilogRom,
+}
+impl OutputFormat {
+    /// The format used for a file (auto picks it based on the extension)
+    pub fn of(self, path: &Path) -> OutputFormat {
+        if self != OutputFormat::Auto {
+            return self;
+        }
+        match path.extension().and_then(OsStr::to_str) {
+            Some("hex") => OutputFormat::Hex,
+            Some("memh") => OutputFormat::Memh,
+            Some("v") => OutputFormat::VerilogRom,
+            _ => OutputFormat::Bin,
+        }
+    }
+
+    /// The extension of files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Auto | OutputFormat::Bin => "bin",
+            OutputFormat::Hex => "hex",
+            OutputFormat::Digital => "txt",
+            OutputFormat::Memh => "memh",
+            OutputFormat::VerilogRom => "v",
+        }
+    }
+}
+
+/// The content of an output file
+pub fn write(program: &Program, format: OutputFormat, path: &Path) -> Vec<u8> {
+    match format.of(path) {
+        OutputFormat::Auto | OutputFormat::Bin => write_bin(program.banks.clone()),
+        OutputFormat::Hex => write_hex(program.banks.clone()),
+        OutputFormat::Digital => write_digital(program.banks.clone()),
+        OutputFormat::Memh => verilog::write_memh(program).into_bytes(),
+        OutputFormat::VerilogRom => {
+            let module = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(verilog::module_name)
+                .unwrap_or_else(|| "rom".to_string());
+            verilog::write_verilog_rom(program, &module).into_bytes()
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Projects with multiple programs described by an `nna.toml` manifest
+//!
+//! ```toml
+//! arch = "nna8v2"
+//! out_dir = "bin"
+//! formats = ["hex"]
+//!
+//! [defines]
+//! DEBUG = 1
+//!
+//! [[program]]
+//! input = "hello_world.asm"
+//! formats = ["hex", "memh"]
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use libnna::Architecture;
+use serde::Deserialize;
+
+use crate::{
+    asm::{reserved_name, Symbols},
+    deps,
+    output::OutputFormat,
+    Options,
+};
+
+pub const MANIFEST: &str = "nna.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    arch: Option<String>,
+    #[serde(default = "default_out_dir")]
+    out_dir: PathBuf,
+    #[serde(default = "default_formats")]
+    formats: Vec<OutputFormat>,
+    #[serde(default)]
+    include_dirs: Vec<PathBuf>,
+    #[serde(default)]
+    defines: HashMap<String, u64>,
+    #[serde(default)]
+    optimize: bool,
+    #[serde(default, rename = "program")]
+    programs: Vec<ProgramManifest>,
+}
+
+/// A program in the manifest (the settings default to the ones of the project)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProgramManifest {
+    input: PathBuf,
+    /// The name of the output files (defaults to the name of the input)
+    name: Option<String>,
+    arch: Option<String>,
+    formats: Option<Vec<OutputFormat>>,
+    /// Searched after the ones of the project
+    #[serde(default)]
+    include_dirs: Vec<PathBuf>,
+    /// Added to the ones of the project
+    #[serde(default)]
+    defines: HashMap<String, u64>,
+    optimize: Option<bool>,
+}
+
+fn default_out_dir() -> PathBuf {
+    "bin".into()
+}
+
+fn default_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Bin]
+}
+
+/// A program to build
+pub struct Target {
+    pub name: String,
+    pub input: PathBuf,
+    pub outputs: Vec<(PathBuf, OutputFormat)>,
+    pub arch: Architecture,
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Symbols,
+    pub optimize: bool,
+}
+impl Target {
+    /// The assembler options (without where files are read from)
+    pub fn options(&self) -> Options<'static> {
+        Options {
+            defines: self.defines.clone(),
+            path: Some(self.input.clone()),
+            include_paths: self.include_dirs.clone(),
+            optimize: self.optimize,
+            ..Options::new(self.arch)
+        }
+    }
+
+    /// Where the files this target was last built from are listed
+    pub fn depfile(&self) -> PathBuf {
+        let mut path = self.outputs[0].0.clone().into_os_string();
+        path.push(".d");
+        path.into()
+    }
+
+    /// If the outputs are newer than the manifest and every file read when they were last built
+    pub fn up_to_date(&self, manifest: &Path) -> bool {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+        let depfile = self.depfile();
+        let Ok(content) = fs::read_to_string(&depfile) else {
+            return false;
+        };
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|(path, _)| path.as_path())
+            .chain([depfile.as_path()])
+            .map(modified)
+            .collect::<io::Result<Vec<SystemTime>>>();
+        let inputs = deps::read(&content)
+            .iter()
+            .map(PathBuf::as_path)
+            .chain([manifest, self.input.as_path()])
+            .map(modified)
+            .collect::<io::Result<Vec<SystemTime>>>();
+        match (outputs, inputs) {
+            (Ok(outputs), Ok(inputs)) => inputs.iter().max() <= outputs.iter().min(),
+            _ => false,
+        }
+    }
+}
+
+fn arch(name: &str) -> Result<Architecture, String> {
+    Architecture::from_str(name).map_err(|()| format!("Unknown architecture '{}'", name))
+}
+
+/// Reads a manifest and returns the programs in it.
+/// Without any `[[program]]` every `.asm` file next to the manifest is built.
+pub fn load(path: &Path) -> Result<Vec<Target>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read '{}'\n{}", path.display(), err))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    parse(&content, dir).map_err(|err| format!("Invalid manifest '{}'\n{}", path.display(), err))
+}
+
+fn parse(content: &str, dir: &Path) -> Result<Vec<Target>, String> {
+    let mut manifest: Manifest = toml::from_str(content).map_err(|err| err.to_string())?;
+    let defines = manifest.defines.keys().chain(
+        manifest
+            .programs
+            .iter()
+            .flat_map(|program| program.defines.keys()),
+    );
+    for name in defines {
+        if let Some(kind) = reserved_name(name) {
+            return Err(format!("'{}' is {} and can't be defined", name, kind));
+        }
+    }
+    if manifest.programs.is_empty() {
+        let mut inputs: Vec<PathBuf> = fs::read_dir(dir.join("."))
+            .map_err(|err| err.to_string())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| PathBuf::from(entry.file_name()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+            .collect();
+        inputs.sort();
+        manifest.programs = inputs
+            .into_iter()
+            .map(|input| ProgramManifest {
+                input,
+                name: None,
+                arch: None,
+                formats: None,
+                include_dirs: Vec::new(),
+                defines: HashMap::new(),
+                optimize: None,
+            })
+            .collect();
+    }
+
+    let default_arch = match &manifest.arch {
+        Some(name) => arch(name)?,
+        None => Architecture::Nna8v1,
+    };
+    let mut targets = Vec::new();
+    for program in manifest.programs {
+        let name = match program.name {
+            Some(name) => name,
+            None => program
+                .input
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| format!("'{}' has no file name", program.input.display()))?
+                .to_string(),
+        };
+        let formats = program.formats.unwrap_or(manifest.formats.clone());
+        if formats.is_empty() {
+            return Err(format!("'{}' has no output formats", name));
+        }
+        let outputs = formats
+            .into_iter()
+            .map(|format| {
+                let file = format!("{}.{}", name, format.extension());
+                (dir.join(&manifest.out_dir).join(file), format)
+            })
+            .collect();
+        let defines = manifest
+            .defines
+            .iter()
+            .chain(program.defines.iter())
+            .map(|(name, value)| (name.as_str().into(), *value))
+            .collect();
+        targets.push(Target {
+            input: dir.join(&program.input),
+            outputs,
+            arch: match &program.arch {
+                Some(name) => arch(name)?,
+                None => default_arch,
+            },
+            include_dirs: manifest
+                .include_dirs
+                .iter()
+                .chain(program.include_dirs.iter())
+                .map(|path| dir.join(path))
+                .collect(),
+            defines,
+            optimize: program.optimize.unwrap_or(manifest.optimize),
+            name,
+        });
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use libnna::Architecture;
+
+    use super::parse;
+    use crate::output::OutputFormat;
+
+    #[test]
+    fn manifest() {
+        let targets = parse(
+            r#"
+arch = "nna8v2"
+formats = ["hex"]
+include_dirs = ["lib"]
+defines = { DEBUG = 1, SIZE = 4 }
+
+[[program]]
+input = "src/main.asm"
+
+[[program]]
+input = "test.asm"
+name = "tests"
+arch = "nna8v1"
+formats = ["bin", "verilog-rom"]
+defines = { SIZE = 8 }
+"#,
+            Path::new("proj"),
+        )
+        .unwrap();
+        assert_eq!(targets.len(), 2);
+        let main = &targets[0];
+        assert_eq!(main.name, "main");
+        assert_eq!(main.input, Path::new("proj/src/main.asm"));
+        assert_eq!(main.arch, Architecture::Nna8v2);
+        assert_eq!(
+            main.outputs,
+            [(Path::new("proj/bin/main.hex").into(), OutputFormat::Hex)]
+        );
+        assert_eq!(main.include_dirs, [Path::new("proj/lib")]);
+        assert_eq!(main.depfile(), Path::new("proj/bin/main.hex.d"));
+
+        let test = &targets[1];
+        assert_eq!(test.arch, Architecture::Nna8v1);
+        assert_eq!(test.outputs[1].0, Path::new("proj/bin/tests.v"));
+        assert_eq!(test.defines.get("SIZE"), Some(&8));
+        assert_eq!(test.defines.get("DEBUG"), Some(&1));
+
+        assert!(parse(
+            "arch = \"nna9\"\n[[program]]\ninput = \"a.asm\"",
+            Path::new("")
+        )
+        .is_err());
+        assert!(parse("[[program]]\nsource = \"a.asm\"", Path::new("")).is_err());
+        assert!(parse(
+            "[[program]]\ninput = \"a.asm\"\ndefines = { nop = 1 }",
+            Path::new("")
+        )
+        .is_err());
+    }
+}
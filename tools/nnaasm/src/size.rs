@@ -0,0 +1,94 @@
+//! Reports about how much memory a program uses
+
+use std::fmt::Write;
+
+use crate::asm::codegen::BankUsage;
+
+/// A human readable report of the memory usage
+pub fn text(usage: &[BankUsage]) -> String {
+    let mut out = String::new();
+    for bank in usage {
+        let _ = write!(
+            out,
+            "bank {:#x} Using {}/{} bytes",
+            bank.bank,
+            bank.used(),
+            bank.size
+        );
+        match bank.largest_free() {
+            Some(free) => {
+                let _ = writeln!(
+                    out,
+                    " (largest free block: {:#04x}..{:#04x}, {} bytes)",
+                    free.start,
+                    free.end,
+                    free.len()
+                );
+            }
+            None => out.push('\n'),
+        }
+        for org in &bank.orgs {
+            let _ = writeln!(out, "  {}", org);
+        }
+        for free in &bank.free {
+            let _ = writeln!(
+                out,
+                "  free {:#04x}..{:#04x} ({} bytes)",
+                free.start,
+                free.end,
+                free.len()
+            );
+        }
+    }
+    out
+}
+
+/// The memory usage as JSON
+pub fn json(usage: &[BankUsage]) -> String {
+    let ranges = |ranges: &mut dyn Iterator<Item = (usize, usize)>| {
+        ranges
+            .map(|(start, size)| format!("{{\"start\":{},\"size\":{}}}", start, size))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let banks: Vec<String> = usage
+        .iter()
+        .map(|bank| {
+            format!(
+                "{{\"bank\":{},\"size\":{},\"used\":{},\"largest_free\":{},\"orgs\":[{}],\"free\":[{}]}}",
+                bank.bank,
+                bank.size,
+                bank.used(),
+                bank.largest_free().map_or(0, |free| free.len()),
+                ranges(&mut bank.orgs.iter().map(|org| (org.start_addr as usize, org.size as usize))),
+                ranges(&mut bank.free.iter().map(|free| (free.start, free.len()))),
+            )
+        })
+        .collect();
+    format!("{{\"banks\":[{}]}}", banks.join(","))
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use crate::asm::codegen::{calc_mem_usage, Org};
+
+    use super::json;
+
+    #[test]
+    fn size_report_json() {
+        let usage = calc_mem_usage(
+            &[Org {
+                start_addr: 0x10,
+                bank: 1,
+                size: 0x20,
+            }],
+            Architecture::Nna8v2,
+        );
+        assert_eq!(
+            json(&usage),
+            r#"{"banks":[{"bank":1,"size":256,"used":32,"largest_free":208,"orgs":[{"start":16,"size":32}],"free":[{"start":0,"size":16},{"start":48,"size":208}]}]}"#
+        );
+    }
+}
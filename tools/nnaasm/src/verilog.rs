@@ -0,0 +1,150 @@
+//! Output formats for loading programs into Verilog designs (for example a tinynna on an FPGA)
+
+use std::fmt::Write;
+
+use crate::asm::Program;
+
+/// Writes a file that can be loaded with `$readmemh`.
+/// Every .org starts with an `@addr` marker followed by one byte per line.
+/// The address of bank `n` starts at `n*0x100`.
+pub fn write_memh(program: &Program) -> String {
+    let mut orgs = program.orgs.clone();
+    orgs.sort_by_key(|org| (org.bank, org.start_addr));
+
+    let mut out = String::new();
+    for org in orgs.iter().filter(|org| org.size > 0) {
+        let start = org.start_addr as usize;
+        let bank = &program.banks[org.bank as usize];
+        writeln!(out, "@{:04x}", org.bank as usize * 256 + start).unwrap();
+        for byte in &bank[start..start + org.size as usize] {
+            writeln!(out, "{:02x}", byte).unwrap();
+        }
+    }
+    out
+}
+
+/// Generates a synthesizable ROM module that maps `addr` to `data` using a `case` statement.
+/// The address is `bank*0x100 + addr` and is as wide as needed to address every bank.
+pub fn write_verilog_rom(program: &Program, module: &str) -> String {
+    let size = program.banks.len() * 256;
+    let addr_bits = (usize::BITS - (size - 1).leading_zeros()) as usize;
+    let addr_digits = addr_bits.div_ceil(4);
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by nnaasm").unwrap();
+    writeln!(out, "module {} (", module).unwrap();
+    writeln!(out, "    input wire [{}:0] addr,", addr_bits - 1).unwrap();
+    writeln!(out, "    output reg [7:0] data").unwrap();
+    writeln!(out, ");").unwrap();
+    writeln!(out, "    always @(*) begin").unwrap();
+    writeln!(out, "        case (addr)").unwrap();
+    for (addr, byte) in program.banks.iter().flatten().enumerate() {
+        if *byte == 0 {
+            continue;
+        }
+        writeln!(
+            out,
+            "            {}'h{:0width$x}: data = 8'h{:02x};",
+            addr_bits,
+            addr,
+            byte,
+            width = addr_digits
+        )
+        .unwrap();
+    }
+    writeln!(out, "            default: data = 8'h00;").unwrap();
+    writeln!(out, "        endcase").unwrap();
+    writeln!(out, "    end").unwrap();
+    writeln!(out, "endmodule").unwrap();
+    out
+}
+
+/// Turns a file name into a valid Verilog module name
+pub fn module_name(name: &str) -> String {
+    let mut module: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !module.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        module.insert_str(0, "rom_");
+    }
+    module
+}
+
+#[cfg(test)]
+mod test {
+    use libnna::Architecture;
+
+    use crate::asm::{codegen::Org, Program};
+
+    use super::{module_name, write_memh, write_verilog_rom};
+
+    fn program() -> Program {
+        let mut banks = vec![[0; 256]; 2];
+        banks[0][..3].copy_from_slice(&[0x12, 0x00, 0x34]);
+        banks[0][0x10] = 0xAB;
+        banks[1][0x80] = 0xFF;
+        Program {
+            arch: Architecture::Nna8v2,
+            banks,
+            orgs: vec![
+                Org {
+                    start_addr: 0x10,
+                    bank: 0,
+                    size: 1,
+                },
+                Org {
+                    start_addr: 0x80,
+                    bank: 1,
+                    size: 1,
+                },
+                Org {
+                    start_addr: 0x00,
+                    bank: 0,
+                    size: 3,
+                },
+                Org {
+                    start_addr: 0x40,
+                    bank: 0,
+                    size: 0,
+                },
+            ],
+            instructions: Vec::new(),
+            data: Vec::new(),
+            symbols: Vec::new(),
+            references: Vec::new(),
+            diagnostics: Vec::new(),
+            includes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn memh() {
+        assert_eq!(
+            write_memh(&program()),
+            "@0000\n12\n00\n34\n@0010\nab\n@0180\nff\n"
+        );
+    }
+
+    #[test]
+    fn verilog_rom() {
+        let rom = write_verilog_rom(&program(), "rom");
+        assert!(rom.contains("module rom (\n"));
+        assert!(rom.contains("input wire [8:0] addr,"));
+        assert!(rom.contains("9'h000: data = 8'h12;\n            9'h002: data = 8'h34;"));
+        assert!(rom.contains("9'h180: data = 8'hff;"));
+        assert!(!rom.contains("9'h001"));
+        assert!(rom.contains("default: data = 8'h00;"));
+
+        let mut single = program();
+        single.banks.truncate(1);
+        assert!(write_verilog_rom(&single, "rom").contains("input wire [7:0] addr,"));
+    }
+
+    #[test]
+    fn module_names() {
+        assert_eq!(module_name("hello_world"), "hello_world");
+        assert_eq!(module_name("hello-world.v2"), "hello_world_v2");
+        assert_eq!(module_name("8bit"), "rom_8bit");
+    }
+}
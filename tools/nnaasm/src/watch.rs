@@ -0,0 +1,98 @@
+//! Waiting for files to change (for `--watch`)
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// How long to wait for an editor to finish writing before reassembling
+const SETTLE: Duration = Duration::from_millis(50);
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Uses inotify to wait for changes
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    inotify: inotify::Inotify,
+    /// The watched directories
+    dirs: Vec<(inotify::WatchDescriptor, PathBuf)>,
+}
+#[cfg(target_os = "linux")]
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            inotify: inotify::Inotify::init()?,
+            dirs: Vec::new(),
+        })
+    }
+
+    /// Blocks until one of the files is written, created or replaced
+    pub fn wait(&mut self, files: &[PathBuf]) -> io::Result<()> {
+        use inotify::WatchMask;
+
+        let files: Vec<PathBuf> = files.iter().map(|path| absolute(path)).collect();
+        // the directories are watched because editors often replace a file instead of writing to it
+        for dir in files.iter().filter_map(|path| path.parent()) {
+            if !self.dirs.iter().any(|(_, watched)| watched == dir) {
+                let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE;
+                let wd = self.inotify.watches().add(dir, mask)?;
+                self.dirs.push((wd, dir.to_path_buf()));
+            }
+        }
+
+        let mut buffer = [0; 4096];
+        'wait: loop {
+            for event in self.inotify.read_events_blocking(&mut buffer)? {
+                let Some((_, dir)) = self.dirs.iter().find(|(wd, _)| *wd == event.wd) else {
+                    continue;
+                };
+                if event
+                    .name
+                    .is_some_and(|name| files.contains(&dir.join(name)))
+                {
+                    break 'wait;
+                }
+            }
+        }
+        thread::sleep(SETTLE);
+        // the rest of the writes that happened while settling
+        let _ = self.inotify.read_events(&mut buffer);
+        Ok(())
+    }
+}
+
+/// Polls the modification times of the files
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher;
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    const INTERVAL: Duration = Duration::from_millis(300);
+
+    pub fn new() -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    /// Blocks until one of the files is written, created or replaced
+    pub fn wait(&mut self, files: &[PathBuf]) -> io::Result<()> {
+        let modified = || -> Vec<_> {
+            files
+                .iter()
+                .map(|path| {
+                    std::fs::metadata(absolute(path))
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                })
+                .collect()
+        };
+        let before = modified();
+        while modified() == before {
+            thread::sleep(Self::INTERVAL);
+        }
+        thread::sleep(SETTLE);
+        Ok(())
+    }
+}